@@ -0,0 +1,249 @@
+use anchor_client::{
+    solana_client::nonblocking::rpc_client::RpcClient,
+    solana_sdk::{
+        pubkey::Pubkey,
+        signature::{Keypair, Signature},
+        signer::Signer,
+        system_instruction,
+        transaction::Transaction,
+    },
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{self, FeesConfig},
+    error::AggregatorError,
+    fees::{self, ComputeBudget},
+    lp::instructions::{
+        LP_DEPOSIT_COMPUTE_UNITS, LP_SWAP_COMPUTE_UNITS, LP_WITHDRAW_COMPUTE_UNITS,
+    },
+    metrics, signer,
+    submitter::{Submission, Submitter},
+    vault::instructions::VAULT_FILL_WITHDRAW_COMPUTE_UNITS,
+};
+
+/// Base fee per transaction signature
+pub const LAMPORTS_PER_SIGNATURE: u64 = 5_000;
+
+/// Rent-exempt minimum of an SPL token account, a fill or deposit may create one
+pub const TOKEN_ACCOUNT_RENT_LAMPORTS: u64 = 2_039_280;
+
+/// Transactions a unit of work may send, used to size what the aggregator signer must hold
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BatchPlan {
+    pub fills: u64,
+    /// LP withdraws, each with its swap back
+    pub lp_withdraws: u64,
+    /// LP deposits, each with its swap in
+    pub lp_deposits: u64,
+}
+
+impl BatchPlan {
+    /// Worst case for a batch of withdraw requests: every request filled after an LP unwind
+    /// in each of `pools`
+    pub fn withdraw_batch(requests: usize, pools: usize) -> Self {
+        Self {
+            fills: requests as u64,
+            lp_withdraws: requests as u64 * pools as u64,
+            lp_deposits: 0,
+        }
+    }
+
+    pub fn lp_deposit() -> Self {
+        Self {
+            lp_deposits: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Worst case for recalling LP: a withdraw from each of `pools`
+    pub fn lp_recall(pools: usize) -> Self {
+        Self {
+            lp_withdraws: pools as u64,
+            ..Self::default()
+        }
+    }
+
+    /// Lamports the plan can cost if every transaction lands at the maximum compute unit
+    /// price with its fallback compute units, plus rent for a new token account per fill and
+    /// deposit. Expired attempts never land, so only one attempt per transaction pays.
+    pub fn required_lamports(&self, config: &FeesConfig) -> u64 {
+        let transaction = |units: u32| {
+            let budget = ComputeBudget {
+                unit_limit: fees::unit_limit(units as u64, config.compute_unit_margin_bps),
+                unit_price: config.max_compute_unit_price,
+            };
+            LAMPORTS_PER_SIGNATURE.saturating_add(budget.priority_fee_lamports())
+        };
+
+        let fill = transaction(VAULT_FILL_WITHDRAW_COMPUTE_UNITS)
+            .saturating_add(TOKEN_ACCOUNT_RENT_LAMPORTS);
+        let lp_withdraw = transaction(LP_WITHDRAW_COMPUTE_UNITS + LP_SWAP_COMPUTE_UNITS);
+        let lp_deposit = transaction(LP_SWAP_COMPUTE_UNITS + LP_DEPOSIT_COMPUTE_UNITS)
+            .saturating_add(TOKEN_ACCOUNT_RENT_LAMPORTS);
+
+        fill.saturating_mul(self.fills)
+            .saturating_add(lp_withdraw.saturating_mul(self.lp_withdraws))
+            .saturating_add(lp_deposit.saturating_mul(self.lp_deposits))
+    }
+}
+
+/// Checks the aggregator signer can pay for the work about to start
+pub struct BalanceGuard {
+    payer: Pubkey,
+    /// Wallet to top up from, see `BalanceConfig::funding_keypair_path`
+    funding: Option<Keypair>,
+}
+
+impl BalanceGuard {
+    pub fn new(payer: Pubkey) -> Result<Self, String> {
+        let funding = config::get()
+            .balance
+            .funding_keypair_path
+            .as_deref()
+            .map(signer::read_keypair_file)
+            .transpose()?;
+        Ok(Self { payer, funding })
+    }
+
+    /// Fail unless the signer holds what `plan` needs plus the configured reserve, topping
+    /// it up from the funding wallet first when one is configured. Dry runs only warn.
+    pub async fn ensure(&self, rpc: &RpcClient, plan: &BatchPlan) -> Result<(), AggregatorError> {
+        let config = config::get();
+        let required = plan
+            .required_lamports(&config.fees)
+            .saturating_add(config.balance.reserve_lamports);
+
+        let balance = self.balance(rpc).await?;
+        if balance >= required {
+            return Ok(());
+        }
+
+        let shortfall = required - balance;
+        error!(
+            payer = %self.payer,
+            balance,
+            required,
+            shortfall,
+            ?plan,
+            "ALERT: aggregator signer balance too low"
+        );
+
+        if config.aggregator.dry_run {
+            warn!("Dry run: continuing without a top-up");
+            return Ok(());
+        }
+
+        if let Some(funding) = &self.funding {
+            let amount = shortfall.max(config.balance.top_up_lamports);
+            match self.top_up(rpc, funding, amount).await {
+                Ok(signature) => {
+                    info!(
+                        funding = %funding.pubkey(),
+                        amount,
+                        %signature,
+                        "Topped up aggregator signer"
+                    );
+                    let balance = self.balance(rpc).await?;
+                    if balance >= required {
+                        return Ok(());
+                    }
+                    return Err(AggregatorError::InsufficientFunds { balance, required });
+                }
+                Err(e) => warn!(funding = %funding.pubkey(), error = %e, "Top-up failed"),
+            }
+        }
+
+        Err(AggregatorError::InsufficientFunds { balance, required })
+    }
+
+    async fn balance(&self, rpc: &RpcClient) -> Result<u64, AggregatorError> {
+        let balance = rpc
+            .get_balance(&self.payer)
+            .await
+            .map_err(|e| AggregatorError::Rpc {
+                context: "Failed to get aggregator balance",
+                message: e.to_string(),
+            })?;
+        metrics::observe_signer_balance(balance);
+        Ok(balance)
+    }
+
+    /// Transfer `amount` lamports from `funding` to the signer, `funding` pays the fee
+    async fn top_up(
+        &self,
+        rpc: &RpcClient,
+        funding: &Keypair,
+        amount: u64,
+    ) -> Result<Signature, AggregatorError> {
+        const CONTEXT: &str = "Failed to send top-up transaction";
+
+        let blockhash = rpc
+            .get_latest_blockhash()
+            .await
+            .map_err(|e| AggregatorError::Rpc {
+                context: "Failed to get latest blockhash",
+                message: e.to_string(),
+            })?;
+        let tx = Transaction::new_signed_with_payer(
+            &[system_instruction::transfer(
+                &funding.pubkey(),
+                &self.payer,
+                amount,
+            )],
+            Some(&funding.pubkey()),
+            &[funding],
+            blockhash,
+        );
+
+        // Not re-signed on expiry, the next tick checks the balance again
+        match Submitter::new(rpc, &config::get().rpc)
+            .submit(&tx, CONTEXT)
+            .await?
+        {
+            Submission::Landed(signature) => Ok(signature),
+            Submission::Expired => Err(AggregatorError::Rpc {
+                context: CONTEXT,
+                message: format!("{} expired before landing", tx.signatures[0]),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn withdraw_batch_assumes_an_unwind_per_pool() {
+        assert_eq!(
+            BatchPlan::withdraw_batch(3, 2),
+            BatchPlan {
+                fills: 3,
+                lp_withdraws: 6,
+                lp_deposits: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn required_lamports_prices_every_transaction_at_the_max() {
+        let config = FeesConfig {
+            compute_unit_margin_bps: 0,
+            max_compute_unit_price: 1_000_000, // 1 lamport per unit
+            ..FeesConfig::default()
+        };
+        let fill = LAMPORTS_PER_SIGNATURE
+            + VAULT_FILL_WITHDRAW_COMPUTE_UNITS as u64
+            + TOKEN_ACCOUNT_RENT_LAMPORTS;
+        let lp_withdraw = LAMPORTS_PER_SIGNATURE
+            + LP_WITHDRAW_COMPUTE_UNITS as u64
+            + LP_SWAP_COMPUTE_UNITS as u64;
+
+        assert_eq!(
+            BatchPlan::withdraw_batch(2, 1).required_lamports(&config),
+            2 * fill + 2 * lp_withdraw
+        );
+        assert_eq!(BatchPlan::default().required_lamports(&config), 0);
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::{config::BufferConfig, memepool};
+
+/// What to do with the vault's liquid SOL this tick
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rebalance {
+    /// Deposit this many lamports into LP
+    Deploy(u64),
+    /// Unwind LP for this many lamports
+    Recall(u64),
+    /// Within the band, or the gap is smaller than a chunk
+    Hold,
+}
+
+/// The buffer's target this tick and what rebalancing does about it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Plan {
+    /// Lamports to keep liquid, see `target_lamports`
+    pub target_lamports: u64,
+    /// Drift from the target tolerated before deploying or recalling, in lamports
+    pub band_lamports: u64,
+    pub rebalance: Rebalance,
+}
+
+/// Keeps a target share of `Vault.lamports` liquid, sized by recent fill volume. Fills are
+/// only remembered in memory, after a restart the buffer starts from `target_bps` alone.
+#[derive(Default)]
+pub struct LiquidityBuffer {
+    fills: VecDeque<(Instant, u64)>,
+}
+
+impl LiquidityBuffer {
+    pub fn record_fill(&mut self, lamports: u64) {
+        self.fills.push_back((Instant::now(), lamports));
+    }
+
+    /// Lamports filled within the last `window`
+    fn recent_volume(&mut self, window: Duration) -> u64 {
+        while self
+            .fills
+            .front()
+            .is_some_and(|(at, _)| at.elapsed() > window)
+        {
+            self.fills.pop_front();
+        }
+        self.fills.iter().fold(0u64, |volume, &(_, lamports)| {
+            volume.saturating_add(lamports)
+        })
+    }
+
+    pub fn plan(&mut self, config: &BufferConfig, vault: &memepool::accounts::Vault) -> Plan {
+        let volume = self.recent_volume(Duration::from_secs(config.volume_window_secs));
+        Plan {
+            target_lamports: target_lamports(config, vault.lamports, volume),
+            band_lamports: bps(vault.lamports, config.band_bps),
+            rebalance: rebalance(config, vault.lamports, vault.available_lamports, volume),
+        }
+    }
+}
+
+/// Lamports to keep liquid: `target_bps` of the vault, or enough to cover recent fill volume
+/// if that is more, never more than the vault holds
+pub fn target_lamports(config: &BufferConfig, lamports: u64, recent_volume: u64) -> u64 {
+    bps(lamports, config.target_bps)
+        .max(bps(recent_volume, config.volume_coverage_bps))
+        .min(lamports)
+}
+
+fn rebalance(
+    config: &BufferConfig,
+    lamports: u64,
+    available_lamports: u64,
+    recent_volume: u64,
+) -> Rebalance {
+    let target = target_lamports(config, lamports, recent_volume);
+    let band = bps(lamports, config.band_bps);
+    let chunk = |gap: u64| {
+        let chunk = gap.min(config.max_chunk_lamports);
+        (chunk >= config.min_chunk_lamports).then_some(chunk)
+    };
+
+    if available_lamports > target.saturating_add(band) {
+        chunk(available_lamports - target).map_or(Rebalance::Hold, Rebalance::Deploy)
+    } else if available_lamports.saturating_add(band) < target {
+        chunk(target - available_lamports).map_or(Rebalance::Hold, Rebalance::Recall)
+    } else {
+        Rebalance::Hold
+    }
+}
+
+fn bps(lamports: u64, bps: u64) -> u64 {
+    (lamports as u128 * bps as u128 / 10_000) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOL: u64 = 1_000_000_000;
+
+    fn config() -> BufferConfig {
+        BufferConfig {
+            target_bps: 1_000,
+            band_bps: 200,
+            volume_coverage_bps: 10_000,
+            min_chunk_lamports: SOL / 100,
+            max_chunk_lamports: 5 * SOL,
+            ..BufferConfig::default()
+        }
+    }
+
+    #[test]
+    fn deploys_and_recalls_back_to_the_target() {
+        let config = config();
+        // 100 SOL vault, 10 SOL target, 2 SOL band
+        assert_eq!(rebalance(&config, 100 * SOL, 11 * SOL, 0), Rebalance::Hold);
+        assert_eq!(rebalance(&config, 100 * SOL, 9 * SOL, 0), Rebalance::Hold);
+        assert_eq!(
+            rebalance(&config, 100 * SOL, 13 * SOL, 0),
+            Rebalance::Deploy(3 * SOL)
+        );
+        assert_eq!(
+            rebalance(&config, 100 * SOL, 7 * SOL, 0),
+            Rebalance::Recall(3 * SOL)
+        );
+        // Chunks are capped, the rest follows on later ticks
+        assert_eq!(
+            rebalance(&config, 100 * SOL, 100 * SOL, 0),
+            Rebalance::Deploy(5 * SOL)
+        );
+    }
+
+    #[test]
+    fn recent_volume_raises_the_target() {
+        let config = config();
+        assert_eq!(target_lamports(&config, 100 * SOL, 25 * SOL), 25 * SOL);
+        assert_eq!(target_lamports(&config, 100 * SOL, 200 * SOL), 100 * SOL);
+        assert_eq!(
+            rebalance(&config, 100 * SOL, 13 * SOL, 25 * SOL),
+            Rebalance::Recall(5 * SOL)
+        );
+    }
+
+    #[test]
+    fn holds_gaps_smaller_than_a_chunk() {
+        let config = config();
+        // 1 SOL vault, 0.1 SOL target, 0.02 SOL band, 0.01 SOL minimum chunk
+        assert_eq!(
+            rebalance(&config, SOL, SOL / 8, 0),
+            Rebalance::Deploy(SOL / 40)
+        );
+        let config = BufferConfig {
+            min_chunk_lamports: SOL / 20,
+            ..config
+        };
+        assert_eq!(rebalance(&config, SOL, SOL / 8, 0), Rebalance::Hold);
+    }
+}
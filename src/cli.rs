@@ -0,0 +1,74 @@
+use std::path::PathBuf;
+
+use anchor_client::Cluster;
+use clap::{Parser, Subcommand};
+
+use crate::config::{Config, LogFormat, SignerConfig};
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Fills memepool withdraw requests and manages the vault's LP positions"
+)]
+pub struct Cli {
+    /// Path to the TOML config file [default: aggregator.toml if present]
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Override the cluster: localnet, devnet, mainnet or an http(s) RPC url
+    #[arg(long, global = true)]
+    pub cluster: Option<Cluster>,
+
+    /// Sign with the keypair file at this path, whatever `[signer]` says
+    #[arg(long, global = true)]
+    pub keypair: Option<PathBuf>,
+
+    /// Simulate transactions and print their effects instead of sending them
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Override the log output format
+    #[arg(long, global = true, value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Default)]
+pub enum Command {
+    /// Poll for withdraw requests and deposit idle SOL into LP
+    #[default]
+    Run,
+    /// Interactive LP deposit/withdraw loop for testing
+    Debug,
+    /// List the CPMM pools registered with the vault
+    Pools,
+    /// Print the vault's NAV and its drift from `Vault.lamports` as JSON
+    Nav,
+}
+
+impl Cli {
+    /// Load the config file and apply command line overrides
+    pub fn load_config(&self) -> Result<Config, String> {
+        let mut config = Config::load(self.config.as_deref())?;
+
+        if let Some(cluster) = &self.cluster {
+            config.rpc.cluster = cluster.clone();
+            config.rpc.ws_url = None;
+        }
+        if let Some(keypair) = &self.keypair {
+            config.keypair_path = keypair.clone();
+            config.signer = SignerConfig::File;
+        }
+        if self.dry_run {
+            config.aggregator.dry_run = true;
+        }
+        if let Some(log_format) = self.log_format {
+            config.log.format = log_format;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+}
@@ -1,30 +1,39 @@
-use std::{fs, rc::Rc};
+use std::sync::Arc;
 
-use anchor_client::{solana_sdk::{commitment_config::CommitmentConfig, signature::Keypair}, Client, Cluster, Program};
+use anchor_client::{solana_client::nonblocking::rpc_client::RpcClient, Client, Program};
+use once_cell::sync::Lazy;
 
-use crate::{memepool, utils::CP_SWAP_PROGRAM};
+use crate::{config, memepool, signer::AggregatorSigner, utils::CP_SWAP_PROGRAM};
 
+/// Nonblocking RPC client shared by every task, at the configured commitment
+static RPC: Lazy<RpcClient> = Lazy::new(|| {
+    let config = config::get();
+    RpcClient::new_with_commitment(config.cluster().url().to_string(), config.rpc.commitment)
+});
 
-pub fn load_aggregator_keypair() -> Keypair {
-    let keypair_str = fs::read_to_string("./target/deploy/aggregator-keypair.json")
-        .expect("Failed to read aggregator-keypair.json");
-    
-    let keypair_bytes: Vec<u8> = serde_json::from_str(&keypair_str)
-        .expect("Failed to parse JSON keypair data");
-   
-    assert_eq!(keypair_bytes.len(), 64, "Keypair must be 64 bytes (32 secret + 32 public)");
-   
-    Keypair::from_bytes(&keypair_bytes).expect("Failed to create Keypair from bytes")
+/// Program handles and the signer behind them. `Send + Sync`, so tasks share one behind an `Arc`.
+pub struct Clients {
+    pub program: Program<Arc<AggregatorSigner>>,
+    pub spl_program: Program<Arc<AggregatorSigner>>,
+    pub raydium_program: Program<Arc<AggregatorSigner>>,
+    pub signer: AggregatorSigner,
 }
 
-pub fn get_programs(aggregator_keypair: &Keypair) -> (Program<Rc<Keypair>>, Program<Rc<Keypair>>, Program<Rc<Keypair>>) {
+pub fn get_clients(aggregator_signer: AggregatorSigner) -> Arc<Clients> {
+    let config = config::get();
     let provider = Client::new_with_options(
-        Cluster::Devnet,
-        Rc::new(aggregator_keypair.insecure_clone()),
-        CommitmentConfig::confirmed(),
+        config.cluster(),
+        Arc::new(aggregator_signer.clone()),
+        config.rpc.commitment,
     );
-    let memepool_program = provider.program(memepool::ID).unwrap();
-    let spl_program = provider.program(anchor_spl::token::ID).unwrap();
-    let raydium_program = provider.program(CP_SWAP_PROGRAM).unwrap();
-    (memepool_program, spl_program, raydium_program)
-}
\ No newline at end of file
+    Arc::new(Clients {
+        program: provider.program(memepool::ID).unwrap(),
+        spl_program: provider.program(anchor_spl::token::ID).unwrap(),
+        raydium_program: provider.program(*CP_SWAP_PROGRAM).unwrap(),
+        signer: aggregator_signer,
+    })
+}
+
+pub fn rpc() -> &'static RpcClient {
+    &RPC
+}
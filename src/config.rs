@@ -0,0 +1,466 @@
+use std::{
+    fmt::Display,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anchor_client::{solana_sdk::commitment_config::CommitmentConfig, Cluster};
+use anchor_lang::prelude::{pubkey, Pubkey};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Deserializer};
+
+pub const DEFAULT_CONFIG_PATH: &str = "aggregator.toml";
+
+pub const DEVNET_CP_SWAP_PROGRAM: Pubkey = pubkey!("CPMDWBwJDtYax9qW7AyRuVC19Cc4L4Vcy4n2BHAbHkCW");
+pub const MAINNET_CP_SWAP_PROGRAM: Pubkey = pubkey!("CPMMoo8L3F4NbTegBCKVNunggL7H1ZpdTHKxQB5qKP1C");
+pub const DEFAULT_MEMO_PROGRAM: Pubkey = pubkey!("MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr");
+
+/// Seconds a blockhash can take to expire, 150 slots at a slow 600ms each
+const BLOCKHASH_LIFETIME_SECS: u64 = 90;
+/// Sends a tick can wait on back to back: the LP task it awaits, an LP unwind and a fill
+const SENDS_PER_TICK: u64 = 3;
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// Aggregator configuration, see `aggregator.example.toml` for every option
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub rpc: RpcConfig,
+    pub keypair_path: PathBuf,
+    pub signer: SignerConfig,
+    pub programs: ProgramsConfig,
+    pub aggregator: AggregatorConfig,
+    pub buffer: BufferConfig,
+    pub oracle: OracleConfig,
+    pub fees: FeesConfig,
+    pub balance: BalanceConfig,
+    pub log: LogConfig,
+    pub http: HttpConfig,
+    pub health: HealthConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcConfig {
+    /// localnet, devnet, mainnet or an http(s) RPC url
+    #[serde(deserialize_with = "from_str")]
+    pub cluster: Cluster,
+    /// Websocket url, derived from the RPC url when not set
+    pub ws_url: Option<String>,
+    #[serde(deserialize_with = "from_str")]
+    pub commitment: CommitmentConfig,
+    /// Commitment a sent transaction must reach before it counts as landed
+    #[serde(deserialize_with = "from_str")]
+    pub confirm_commitment: CommitmentConfig,
+    /// Milliseconds between signature status polls
+    pub confirm_poll_interval_ms: u64,
+}
+
+/// Where the aggregator's key lives
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SignerConfig {
+    /// JSON keypair file at `keypair_path`
+    #[default]
+    File,
+    /// Base58 keypair in the environment variable `var`
+    Env { var: String },
+    /// BIP39 mnemonic, and optional passphrase, in environment variables
+    Mnemonic {
+        phrase_var: String,
+        passphrase_var: Option<String>,
+        #[serde(default = "default_derivation_path")]
+        derivation_path: String,
+    },
+    /// HTTP signing service, see `signer::RemoteSigner`
+    Remote {
+        url: String,
+        #[serde(deserialize_with = "from_str")]
+        pubkey: Pubkey,
+        /// Environment variable holding a bearer token for the service
+        token_var: Option<String>,
+        #[serde(default = "default_remote_timeout_ms")]
+        timeout_ms: u64,
+    },
+}
+
+fn default_derivation_path() -> String {
+    "m/44'/501'/0'/0'".to_string()
+}
+
+fn default_remote_timeout_ms() -> u64 {
+    10_000
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProgramsConfig {
+    /// Raydium CPMM program, defaults to the canonical id for a named cluster and is required
+    /// when the cluster is an RPC url
+    #[serde(deserialize_with = "from_str_opt")]
+    pub cp_swap: Option<Pubkey>,
+    #[serde(deserialize_with = "from_str_opt")]
+    pub memo: Option<Pubkey>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AggregatorConfig {
+    /// Seconds between polls for withdraw requests
+    pub poll_interval_secs: u64,
+    /// Extra WSOL, in bps of the shortfall, to unwind from LP to cover quote drift
+    pub withdraw_safety_margin_bps: u64,
+    /// Simulate every transaction and print its effects instead of sending it
+    pub dry_run: bool,
+    /// Append-only JSONL record of every fill and LP operation, reloaded on start
+    pub journal_path: PathBuf,
+    /// Withdraw requests filled at once when the vault already holds their lamports
+    pub max_concurrent_fills: usize,
+    /// Wake on websocket notifications of new withdraw requests, polling stays the fallback
+    pub subscribe: bool,
+    /// Order pending withdraw requests are served in
+    pub queue_policy: QueuePolicy,
+    /// Under `smallest_first`, requests created more than this many slots ago go first, oldest
+    /// first, so large requests can't starve
+    pub max_wait_slots: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueuePolicy {
+    /// Oldest request first, by creation slot
+    #[default]
+    Fifo,
+    /// Smallest request first to fill as many as available SOL covers, overdue requests ahead
+    SmallestFirst,
+    /// When available SOL can't cover every request after unwinding LP, fill each the same
+    /// share of what it is owed
+    ProRata,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeesConfig {
+    /// Compute units requested over the simulated usage, in bps
+    pub compute_unit_margin_bps: u64,
+    /// Percentile of recent prioritization fees on the written accounts to pay
+    pub priority_fee_percentile: u8,
+    /// Compute unit price bounds, in micro-lamports
+    pub min_compute_unit_price: u64,
+    pub max_compute_unit_price: u64,
+    /// Compute unit price increase per retry, in bps of the previous price
+    pub escalation_bps: u64,
+    /// Signed attempts per transaction before giving up, each with a fresh blockhash
+    pub max_attempts: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BufferConfig {
+    /// Share of `Vault.lamports` kept liquid in `available_lamports`, in bps
+    pub target_bps: u64,
+    /// Drift from the target tolerated before deploying or recalling LP, in bps of
+    /// `Vault.lamports`
+    pub band_bps: u64,
+    /// Seconds of fill history that size the buffer
+    pub volume_window_secs: u64,
+    /// Keep at least this share of the window's fill volume liquid, in bps
+    pub volume_coverage_bps: u64,
+    /// Bounds on the lamports moved into or out of LP per tick
+    pub min_chunk_lamports: u64,
+    pub max_chunk_lamports: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OracleConfig {
+    /// Seconds of the pool's `ObservationState` the TWAP is averaged over
+    pub twap_window_secs: u64,
+    /// Gap between the spot price and the TWAP beyond which swaps and deposits are refused,
+    /// in bps of the TWAP
+    pub max_deviation_bps: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BalanceConfig {
+    /// Lamports kept in the aggregator signer on top of what a batch is estimated to need
+    pub reserve_lamports: u64,
+    /// Wallet to top up the aggregator signer from when it runs short, disabled when not set
+    pub funding_keypair_path: Option<PathBuf>,
+    /// Least lamports moved per top-up
+    pub top_up_lamports: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// `tracing` filter directives, `RUST_LOG` takes precedence when set
+    pub filter: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Human,
+    /// One JSON object per line, with the enclosing spans' fields
+    Json,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Address to serve `/metrics`, `/healthz` and `/readyz` on, disabled when not set
+    pub listen: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Seconds without a finished tick before the aggregator counts as wedged, defaults to
+    /// `Config::worst_case_tick_secs` and may not be set below it
+    pub max_tick_age_secs: Option<u64>,
+    /// Aggregator keypair balance below which it isn't ready, in lamports
+    pub min_signer_lamports: u64,
+    /// Drift between the vault's NAV and `Vault.lamports` that raises an alert, in bps
+    pub max_nav_drift_bps: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            rpc: RpcConfig::default(),
+            keypair_path: PathBuf::from("./target/deploy/aggregator-keypair.json"),
+            signer: SignerConfig::default(),
+            programs: ProgramsConfig::default(),
+            aggregator: AggregatorConfig::default(),
+            buffer: BufferConfig::default(),
+            oracle: OracleConfig::default(),
+            fees: FeesConfig::default(),
+            balance: BalanceConfig::default(),
+            log: LogConfig::default(),
+            http: HttpConfig::default(),
+            health: HealthConfig::default(),
+        }
+    }
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        Self {
+            cluster: Cluster::Devnet,
+            ws_url: None,
+            commitment: CommitmentConfig::confirmed(),
+            confirm_commitment: CommitmentConfig::confirmed(),
+            confirm_poll_interval_ms: 500,
+        }
+    }
+}
+
+impl Default for AggregatorConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: 15,
+            withdraw_safety_margin_bps: 100,
+            dry_run: false,
+            journal_path: PathBuf::from("aggregator-journal.jsonl"),
+            max_concurrent_fills: 4,
+            subscribe: true,
+            queue_policy: QueuePolicy::Fifo,
+            max_wait_slots: 1_500, // ~10 minutes
+        }
+    }
+}
+
+impl Default for FeesConfig {
+    fn default() -> Self {
+        Self {
+            compute_unit_margin_bps: 1_000,
+            priority_fee_percentile: 75,
+            min_compute_unit_price: 1_000,
+            max_compute_unit_price: 1_000_000,
+            escalation_bps: 5_000,
+            max_attempts: 3,
+        }
+    }
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        Self {
+            target_bps: 1_000,
+            band_bps: 250,
+            volume_window_secs: 86_400,
+            volume_coverage_bps: 10_000,
+            min_chunk_lamports: 10_000_000,    // 0.01 SOL
+            max_chunk_lamports: 1_000_000_000, // 1 SOL
+        }
+    }
+}
+
+impl Default for OracleConfig {
+    fn default() -> Self {
+        Self {
+            twap_window_secs: 600,
+            max_deviation_bps: 300,
+        }
+    }
+}
+
+impl Default for BalanceConfig {
+    fn default() -> Self {
+        Self {
+            reserve_lamports: 10_000_000, // 0.01 SOL
+            funding_keypair_path: None,
+            top_up_lamports: 100_000_000, // 0.1 SOL
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Human,
+            filter: "info".to_string(),
+        }
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            max_tick_age_secs: None,
+            min_signer_lamports: 10_000_000, // 0.01 SOL
+            max_nav_drift_bps: 200,
+        }
+    }
+}
+
+impl Config {
+    /// Load the config from `path`, falling back to defaults when no path was given
+    /// and `DEFAULT_CONFIG_PATH` does not exist
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Path::new(DEFAULT_CONFIG_PATH),
+            None => return Ok(Self::default()),
+        };
+
+        let config_str = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config {}: {}", path.display(), e))?;
+
+        let config: Self = toml::from_str(&config_str)
+            .map_err(|e| format!("Failed to parse config {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    /// Reject settings that can't work together, once command line overrides are applied
+    pub fn validate(&self) -> Result<(), String> {
+        // A url doesn't say which cluster it serves, so guessing the CPMM id could pick devnet's
+        // on mainnet
+        if self.programs.cp_swap.is_none() && matches!(self.rpc.cluster, Cluster::Custom(..)) {
+            return Err(format!(
+                "programs.cp_swap must be set when rpc.cluster is an RPC url ({})",
+                self.rpc.cluster.url()
+            ));
+        }
+        match self.health.max_tick_age_secs {
+            Some(max_tick_age_secs) if max_tick_age_secs < self.worst_case_tick_secs() => {
+                Err(format!(
+                    "health.max_tick_age_secs = {} is shorter than a worst-case tick of {} seconds",
+                    max_tick_age_secs,
+                    self.worst_case_tick_secs()
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Longest a healthy tick can take: the poll interval plus every send of the tick using
+    /// all of its attempts, each waiting out its blockhash
+    pub fn worst_case_tick_secs(&self) -> u64 {
+        self.aggregator.poll_interval_secs
+            + SENDS_PER_TICK * self.fees.max_attempts as u64 * BLOCKHASH_LIFETIME_SECS
+    }
+
+    pub fn max_tick_age_secs(&self) -> u64 {
+        self.health
+            .max_tick_age_secs
+            .unwrap_or_else(|| self.worst_case_tick_secs())
+    }
+
+    /// Cluster with the websocket override applied
+    pub fn cluster(&self) -> Cluster {
+        match &self.rpc.ws_url {
+            Some(ws_url) => Cluster::Custom(self.rpc.cluster.url().to_string(), ws_url.clone()),
+            None => self.rpc.cluster.clone(),
+        }
+    }
+
+    pub fn cp_swap_program(&self) -> Pubkey {
+        self.programs.cp_swap.unwrap_or(match self.rpc.cluster {
+            Cluster::Mainnet => MAINNET_CP_SWAP_PROGRAM,
+            // `validate` rejects RPC urls without an explicit id
+            _ => DEVNET_CP_SWAP_PROGRAM,
+        })
+    }
+
+    pub fn memo_program(&self) -> Pubkey {
+        self.programs.memo.unwrap_or(DEFAULT_MEMO_PROGRAM)
+    }
+}
+
+/// Install the global config, must be called once before any program id or PDA is used
+pub fn init(config: Config) {
+    CONFIG
+        .set(config)
+        .expect("Config must only be initialized once");
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("Config used before config::init")
+}
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let s = String::deserialize(deserializer)?;
+    T::from_str(&s).map_err(serde::de::Error::custom)
+}
+
+fn from_str_opt<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    from_str(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rpc_url_needs_an_explicit_cp_swap_program() {
+        let mut config = Config::default();
+        config.rpc.cluster = "https://rpc.example.com".parse().unwrap();
+        assert!(config.validate().is_err());
+
+        config.programs.cp_swap = Some(MAINNET_CP_SWAP_PROGRAM);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.cp_swap_program(), MAINNET_CP_SWAP_PROGRAM);
+
+        config.programs.cp_swap = None;
+        config.rpc.cluster = Cluster::Mainnet;
+        assert_eq!(config.cp_swap_program(), MAINNET_CP_SWAP_PROGRAM);
+    }
+}
@@ -1,17 +1,25 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
-use crate::lp;
-use crate::utils::POOL_ADDRESS;
-use anchor_client::solana_sdk::signature::Keypair;
+use crate::lp::{self, quote::PoolQuoter};
+use crate::pool::RegisteredPool;
+use crate::signer::AggregatorSigner;
 use anchor_client::Program;
 
 pub async fn run_interactive_test_loop<'a>(
-    program: &'a Program<Rc<Keypair>>,
-    raydium_program: &'a Program<Rc<Keypair>>,
-    spl_program: &'a Program<Rc<Keypair>>,
-    aggregator_keypair: &'a Keypair,
+    program: &'a Program<Arc<AggregatorSigner>>,
+    raydium_program: &'a Program<Arc<AggregatorSigner>>,
+    spl_program: &'a Program<Arc<AggregatorSigner>>,
+    aggregator_signer: &'a AggregatorSigner,
+    pools: &'a [RegisteredPool],
 ) {
-    println!("Enter operation (d for deposit, w for withdraw, q to quit):");
+    if pools.is_empty() {
+        println!("No vault pools registered, nothing to test");
+        return;
+    }
+
+    let mut selected = 0;
+    println!("Using pool {}", pools[selected].pool_address);
+    println!("Enter operation (d for deposit, w for withdraw, s to quote a swap, p to pick pool, q to quit):");
     let mut input = String::new();
 
     loop {
@@ -26,6 +34,8 @@ pub async fn run_interactive_test_loop<'a>(
             break;
         }
 
+        let pool_address = pools[selected].pool_address;
+
         match trimmed {
             "d" => {
                 println!("Enter LP deposit amount:");
@@ -42,13 +52,19 @@ pub async fn run_interactive_test_loop<'a>(
                             program,
                             raydium_program,
                             spl_program,
-                            aggregator_keypair,
-                            POOL_ADDRESS,
+                            aggregator_signer,
+                            pool_address,
                             amount,
                         )
                         .await
                         {
-                            Ok(_) => println!("Deposit successful"),
+                            Ok(deposit) => println!(
+                                "Deposit successful: {} LP tokens (tx {}), dust {} WSOL, {} tokens",
+                                deposit.lp_token_amount,
+                                deposit.tx,
+                                deposit.dust_token_0,
+                                deposit.dust_token_1
+                            ),
                             Err(e) => println!("Deposit failed: {}", e),
                         }
                     }
@@ -56,25 +72,96 @@ pub async fn run_interactive_test_loop<'a>(
                 }
             }
             "w" => {
-                println!("Withdrawing all LP tokens...");
+                println!("Enter WSOL amount to withdraw (or \"all\"):");
+                let mut amount_input = String::new();
+                if std::io::stdin().read_line(&mut amount_input).is_err() {
+                    println!("Failed to read amount");
+                    continue;
+                }
+
+                let target_wsol = match amount_input.trim() {
+                    "all" => u64::MAX,
+                    amount => match amount.parse::<u64>() {
+                        Ok(amount) => amount,
+                        Err(_) => {
+                            println!("Please enter a valid number");
+                            continue;
+                        }
+                    },
+                };
+
                 match lp::process_lp_withdraw(
                     program,
                     raydium_program,
                     spl_program,
-                    aggregator_keypair,
-                    POOL_ADDRESS,
+                    aggregator_signer,
+                    pool_address,
+                    target_wsol,
                 )
                 .await
                 {
-                    Ok(_) => println!("Withdraw successful"),
+                    Ok(withdraw) => println!(
+                        "Withdraw successful: burned {} LP tokens for ~{} WSOL (tx {})",
+                        withdraw.lp_burned, withdraw.expected_wsol, withdraw.tx
+                    ),
                     Err(e) => println!("Withdraw failed: {}", e),
                 }
             }
+            "s" => {
+                println!("Enter token1 amount to buy with WSOL:");
+                let mut amount_input = String::new();
+                if std::io::stdin().read_line(&mut amount_input).is_err() {
+                    println!("Failed to read amount");
+                    continue;
+                }
+
+                let Ok(amount) = amount_input.trim().parse::<u64>() else {
+                    println!("Please enter a valid number");
+                    continue;
+                };
+                let pool_state = &pools[selected].pool_state;
+                match PoolQuoter::load(raydium_program, spl_program, pool_state)
+                    .await
+                    .and_then(|quoter| quoter.exact_out(amount, true))
+                {
+                    Ok(quote) => println!(
+                        "Buying {} tokens costs {} WSOL (trade fee {}, transfer fees {} in, {} out)",
+                        quote.amount_out,
+                        quote.amount_in,
+                        quote.trade_fee,
+                        quote.transfer_fee_in,
+                        quote.transfer_fee_out
+                    ),
+                    Err(e) => println!("Quote failed: {}", e),
+                }
+            }
+            "p" => {
+                for (i, pool) in pools.iter().enumerate() {
+                    println!(
+                        "{}: {} (vault pool {})",
+                        i, pool.pool_address, pool.vault_pool
+                    );
+                }
+                println!("Enter pool index:");
+                let mut index_input = String::new();
+                if std::io::stdin().read_line(&mut index_input).is_err() {
+                    println!("Failed to read index");
+                    continue;
+                }
+
+                match index_input.trim().parse::<usize>() {
+                    Ok(index) if index < pools.len() => {
+                        selected = index;
+                        println!("Using pool {}", pools[selected].pool_address);
+                    }
+                    _ => println!("Please enter a valid pool index"),
+                }
+            }
             _ => {
-                println!("Invalid operation. Use 'd' for deposit, 'w' for withdraw, or 'q' to quit")
+                println!("Invalid operation. Use 'd' for deposit, 'w' for withdraw, 's' to quote a swap, 'p' to pick pool, or 'q' to quit")
             }
         }
 
-        println!("\nEnter operation (d for deposit, w for withdraw, q to quit):");
+        println!("\nEnter operation (d for deposit, w for withdraw, s to quote a swap, p to pick pool, q to quit):");
     }
 }
@@ -0,0 +1,221 @@
+use anchor_client::{
+    solana_client::{
+        client_error::{ClientError as SolanaClientError, ClientErrorKind},
+        rpc_request::{RpcError, RpcResponseErrorData},
+    },
+    solana_sdk::{instruction::InstructionError, transaction::TransactionError},
+    ClientError,
+};
+use anchor_lang::{prelude::Pubkey, solana_program::program_error::ProgramError};
+use std::str::FromStr;
+
+use crate::{memepool, raydium::PoolGate, utils::CP_SWAP_PROGRAM};
+
+/// Raydium CPMM `ErrorCode::ExceededSlippage`
+const CP_SWAP_EXCEEDED_SLIPPAGE: u32 = 6005;
+
+/// Custom errors of the memepool program, codes 6000-6005 from the IDL
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum MemepoolError {
+    #[error("Error calculating $MEME mint amount")]
+    InvalidMEMEAmount,
+    #[error("Error calculating $SOL transfer amount")]
+    InvalidSOLAmount,
+    #[error("Error calculating vault lamports")]
+    InvalidVault,
+    #[error("Vault OUT OF SOL")]
+    VaultOOS,
+    #[error("Withdraw Request status not ready")]
+    WithdrawRequestNotReady,
+    #[error("Withdraw Request status ready")]
+    WithdrawRequestReady,
+}
+
+impl MemepoolError {
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            6000 => Some(Self::InvalidMEMEAmount),
+            6001 => Some(Self::InvalidSOLAmount),
+            6002 => Some(Self::InvalidVault),
+            6003 => Some(Self::VaultOOS),
+            6004 => Some(Self::WithdrawRequestNotReady),
+            6005 => Some(Self::WithdrawRequestReady),
+            _ => None,
+        }
+    }
+}
+
+/// What the main loop should do with a failed operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// Transient, try again next tick
+    Retry,
+    /// Nothing to do for this item right now, move on
+    Skip,
+    /// Needs a human to look at it
+    Alert,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AggregatorError {
+    #[error("{context}: {message}")]
+    Rpc {
+        context: &'static str,
+        message: String,
+    },
+    #[error("{context}: memepool error {error}")]
+    Memepool {
+        context: &'static str,
+        error: MemepoolError,
+        logs: Vec<String>,
+    },
+    #[error("{context}: {message}")]
+    Program {
+        context: &'static str,
+        program_id: Option<Pubkey>,
+        code: Option<u32>,
+        message: String,
+        logs: Vec<String>,
+    },
+    #[error("Slippage exceeded: {0}")]
+    Slippage(String),
+    #[error("Spot price {deviation_bps} bps away from the TWAP in pool {pool}")]
+    PriceDeviation { pool: Pubkey, deviation_bps: u64 },
+    #[error("Math overflow: {0}")]
+    MathOverflow(&'static str),
+    #[error("Pool {pool} not approved: {gate}")]
+    PoolNotApproved { pool: Pubkey, gate: PoolGate },
+    #[error("Insufficient liquidity: {0}")]
+    InsufficientLiquidity(String),
+    #[error("Retry aborted: {0}")]
+    RetryAborted(String),
+    #[error(
+        "Aggregator balance {balance} is below the {required} lamports the planned work needs"
+    )]
+    InsufficientFunds { balance: u64, required: u64 },
+    #[error("Task failed: {0}")]
+    TaskFailed(String),
+}
+
+impl AggregatorError {
+    /// Classify an anchor client error, decoding memepool custom errors when the
+    /// failing program can be identified from the transaction logs
+    pub fn from_client(context: &'static str, err: ClientError) -> Self {
+        let rpc_err = match err {
+            ClientError::SolanaClientError(rpc_err) => rpc_err,
+            ClientError::ProgramError(program_err) => {
+                let code = match program_err {
+                    ProgramError::Custom(code) => Some(code),
+                    _ => None,
+                };
+                return Self::Program {
+                    context,
+                    program_id: None,
+                    code,
+                    message: program_err.to_string(),
+                    logs: Vec::new(),
+                };
+            }
+            other => {
+                return Self::Rpc {
+                    context,
+                    message: other.to_string(),
+                }
+            }
+        };
+
+        let Some(tx_err) = rpc_err.get_transaction_error() else {
+            return Self::Rpc {
+                context,
+                message: rpc_err.to_string(),
+            };
+        };
+
+        Self::from_transaction_error(context, tx_err, transaction_logs(&rpc_err))
+    }
+
+    /// Classify a failed (or failed simulation of a) transaction from its error and logs
+    pub fn from_transaction_error(
+        context: &'static str,
+        tx_err: TransactionError,
+        logs: Vec<String>,
+    ) -> Self {
+        let program_id = failed_program(&logs);
+        let code = match tx_err {
+            TransactionError::InstructionError(_, InstructionError::Custom(code)) => Some(code),
+            _ => None,
+        };
+
+        if program_id == Some(*CP_SWAP_PROGRAM) && code == Some(CP_SWAP_EXCEEDED_SLIPPAGE) {
+            return Self::Slippage(format!("{}: {}", context, tx_err));
+        }
+
+        match code.and_then(MemepoolError::from_code) {
+            Some(error) if program_id == Some(memepool::ID) => Self::Memepool {
+                context,
+                error,
+                logs,
+            },
+            _ => Self::Program {
+                context,
+                program_id,
+                code,
+                message: tx_err.to_string(),
+                logs,
+            },
+        }
+    }
+
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            Self::Rpc { .. } | Self::Slippage(_) | Self::PriceDeviation { .. } => Recovery::Retry,
+            Self::Memepool { error, .. } => match error {
+                MemepoolError::VaultOOS => Recovery::Retry,
+                MemepoolError::WithdrawRequestNotReady | MemepoolError::WithdrawRequestReady => {
+                    Recovery::Skip
+                }
+                MemepoolError::InvalidMEMEAmount
+                | MemepoolError::InvalidSOLAmount
+                | MemepoolError::InvalidVault => Recovery::Alert,
+            },
+            Self::InsufficientLiquidity(_)
+            | Self::PoolNotApproved { .. }
+            | Self::RetryAborted(_) => Recovery::Skip,
+            Self::Program { .. }
+            | Self::MathOverflow(_)
+            | Self::InsufficientFunds { .. }
+            | Self::TaskFailed(_) => Recovery::Alert,
+        }
+    }
+
+    /// Program logs of the failed transaction, empty when the failure happened before simulation
+    pub fn logs(&self) -> &[String] {
+        match self {
+            Self::Memepool { logs, .. } | Self::Program { logs, .. } => logs,
+            _ => &[],
+        }
+    }
+}
+
+fn transaction_logs(err: &SolanaClientError) -> Vec<String> {
+    match err.kind() {
+        ClientErrorKind::RpcError(RpcError::RpcResponseError {
+            data: RpcResponseErrorData::SendTransactionPreflightFailure(result),
+            ..
+        }) => result.logs.clone().unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// The innermost program that failed, i.e. the first `Program <id> failed: ...` log line
+fn failed_program(logs: &[String]) -> Option<Pubkey> {
+    logs.iter().find_map(|log| {
+        let rest = log.strip_prefix("Program ")?;
+        let (program_id, status) = rest.split_once(' ')?;
+        if status.starts_with("failed") {
+            Pubkey::from_str(program_id).ok()
+        } else {
+            None
+        }
+    })
+}
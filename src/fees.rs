@@ -0,0 +1,172 @@
+use anchor_client::{
+    solana_client::nonblocking::rpc_client::RpcClient,
+    solana_sdk::{
+        compute_budget::ComputeBudgetInstruction, instruction::Instruction, pubkey::Pubkey,
+    },
+};
+
+use crate::{config::FeesConfig, error::AggregatorError, simulation};
+
+/// Most compute units a transaction can request
+pub const MAX_COMPUTE_UNIT_LIMIT: u32 = 1_400_000;
+
+/// `getRecentPrioritizationFees` takes at most this many accounts
+const MAX_PRIORITIZATION_FEE_ACCOUNTS: usize = 128;
+
+/// Compute unit limit and price prepended to a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComputeBudget {
+    pub unit_limit: u32,
+    /// Micro-lamports per compute unit
+    pub unit_price: u64,
+}
+
+impl ComputeBudget {
+    pub fn instructions(&self) -> [Instruction; 2] {
+        [
+            ComputeBudgetInstruction::set_compute_unit_limit(self.unit_limit),
+            ComputeBudgetInstruction::set_compute_unit_price(self.unit_price),
+        ]
+    }
+
+    /// Priority fee paid on top of the base fee, in lamports
+    pub fn priority_fee_lamports(&self) -> u64 {
+        (self.unit_limit as u128 * self.unit_price as u128).div_ceil(1_000_000) as u64
+    }
+}
+
+/// Simulate `instructions` to size the compute unit limit, and price it off the recent
+/// prioritization fees of the accounts they write, escalated for each prior `attempt`.
+///
+/// `fallback_units` is used when the RPC node doesn't report the units consumed.
+pub async fn compute_budget(
+    rpc: &RpcClient,
+    payer: &Pubkey,
+    instructions: &[Instruction],
+    fallback_units: u32,
+    attempt: u32,
+    config: &FeesConfig,
+    context: &'static str,
+) -> Result<ComputeBudget, AggregatorError> {
+    let units_consumed = simulate_compute_units(rpc, payer, instructions, context)
+        .await?
+        .unwrap_or(fallback_units as u64);
+
+    // The accounts the transaction write-locks are the fee markets it competes in
+    let mut accounts = simulation::writable_accounts(payer, instructions);
+    accounts.truncate(MAX_PRIORITIZATION_FEE_ACCOUNTS);
+
+    let fees = rpc
+        .get_recent_prioritization_fees(&accounts)
+        .await
+        .map_err(|e| AggregatorError::Rpc {
+            context: "Failed to get recent prioritization fees",
+            message: e.to_string(),
+        })?
+        .into_iter()
+        .map(|fee| fee.prioritization_fee)
+        .collect();
+
+    let unit_price = percentile(fees, config.priority_fee_percentile)
+        .clamp(config.min_compute_unit_price, config.max_compute_unit_price);
+
+    Ok(ComputeBudget {
+        unit_limit: unit_limit(units_consumed, config.compute_unit_margin_bps),
+        unit_price: escalate(unit_price, attempt, config),
+    })
+}
+
+/// Units consumed by `instructions`, or the program error the transaction would fail with
+pub async fn simulate_compute_units(
+    rpc: &RpcClient,
+    payer: &Pubkey,
+    instructions: &[Instruction],
+    context: &'static str,
+) -> Result<Option<u64>, AggregatorError> {
+    // Simulate at the maximum limit so the runtime default doesn't cut the run short
+    let mut simulated = vec![ComputeBudgetInstruction::set_compute_unit_limit(
+        MAX_COMPUTE_UNIT_LIMIT,
+    )];
+    simulated.extend_from_slice(instructions);
+    let result = simulation::simulate(rpc, payer, &simulated, &[]).await?;
+
+    match result.err {
+        Some(err) => Err(AggregatorError::from_transaction_error(
+            context,
+            err,
+            result.logs.unwrap_or_default(),
+        )),
+        None => Ok(result.units_consumed),
+    }
+}
+
+/// Simulated usage plus the margin, capped at the transaction maximum
+pub fn unit_limit(units_consumed: u64, margin_bps: u64) -> u32 {
+    let with_margin = units_consumed as u128 * (10_000 + margin_bps as u128) / 10_000;
+    with_margin.min(MAX_COMPUTE_UNIT_LIMIT as u128) as u32
+}
+
+/// Nearest-rank percentile, 0 when there are no samples
+pub fn percentile(mut fees: Vec<u64>, percentile: u8) -> u64 {
+    if fees.is_empty() {
+        return 0;
+    }
+    fees.sort_unstable();
+    let rank = (fees.len() * percentile.min(100) as usize).div_ceil(100);
+    fees[rank.saturating_sub(1)]
+}
+
+/// Raise `unit_price` by `escalation_bps` for every prior attempt, capped at the max price
+pub fn escalate(unit_price: u64, attempt: u32, config: &FeesConfig) -> u64 {
+    let mut price = unit_price as u128;
+    for _ in 0..attempt {
+        price = price * (10_000 + config.escalation_bps as u128) / 10_000;
+        if price >= config.max_compute_unit_price as u128 {
+            break;
+        }
+    }
+    price.min(config.max_compute_unit_price as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_limit_adds_margin_and_caps() {
+        assert_eq!(unit_limit(100_000, 1_000), 110_000);
+        assert_eq!(unit_limit(1_300_000, 1_000), MAX_COMPUTE_UNIT_LIMIT);
+    }
+
+    #[test]
+    fn percentile_uses_nearest_rank() {
+        let fees = vec![50, 10, 40, 20, 30];
+        assert_eq!(percentile(fees.clone(), 0), 10);
+        assert_eq!(percentile(fees.clone(), 50), 30);
+        assert_eq!(percentile(fees.clone(), 75), 40);
+        assert_eq!(percentile(fees, 100), 50);
+        assert_eq!(percentile(Vec::new(), 75), 0);
+    }
+
+    #[test]
+    fn escalation_compounds_up_to_max() {
+        let config = FeesConfig {
+            escalation_bps: 5_000,
+            max_compute_unit_price: 5_000,
+            ..FeesConfig::default()
+        };
+        assert_eq!(escalate(1_000, 0, &config), 1_000);
+        assert_eq!(escalate(1_000, 1, &config), 1_500);
+        assert_eq!(escalate(1_000, 2, &config), 2_250);
+        assert_eq!(escalate(1_000, 10, &config), 5_000);
+    }
+
+    #[test]
+    fn priority_fee_rounds_up() {
+        let budget = ComputeBudget {
+            unit_limit: 200_000,
+            unit_price: 1_001,
+        };
+        assert_eq!(budget.priority_fee_lamports(), 201);
+    }
+}
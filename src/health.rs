@@ -0,0 +1,284 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anchor_client::solana_client::nonblocking::rpc_client::RpcClient;
+use anchor_lang::prelude::Pubkey;
+use axum::{extract::State, http::StatusCode, Json};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{
+    buffer::{Plan, Rebalance},
+    config::HealthConfig,
+    memepool,
+    utils::unix_now,
+};
+
+/// RPC calls made by `/readyz` give up after this long
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+static STATE: Lazy<Mutex<TickState>> = Lazy::new(|| {
+    Mutex::new(TickState {
+        started: unix_now(),
+        ..TickState::default()
+    })
+});
+
+/// What the main loop last saw, timestamps in unix seconds
+#[derive(Debug, Clone, Default)]
+struct TickState {
+    /// Process start, stands in for the last tick until the first one finishes
+    started: u64,
+    last_tick: Option<u64>,
+    last_successful_tick: Option<u64>,
+    last_fill: Option<Fill>,
+    pending_withdraw_requests: Option<usize>,
+    /// (lamports, available_lamports)
+    vault: Option<(u64, u64)>,
+    /// The liquidity buffer's plan for that vault
+    buffer: Option<Plan>,
+}
+
+/// What `/readyz` checks live on every request
+#[derive(Clone)]
+pub struct Probe {
+    pub rpc: Arc<RpcClient>,
+    pub signer: Pubkey,
+    pub config: HealthConfig,
+    /// `Config::max_tick_age_secs`, resolved against the poll interval and send attempts
+    pub max_tick_age_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Fill {
+    pub timestamp: u64,
+    pub signature: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub ok: bool,
+    /// Names of the checks that failed
+    pub failing: Vec<&'static str>,
+    pub last_tick: Option<u64>,
+    pub last_successful_tick: Option<u64>,
+    pub last_fill: Option<Fill>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending_withdraw_requests: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rpc: Option<RpcReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signer: Option<SignerReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub liquidity_buffer: Option<BufferReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcReport {
+    pub reachable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slot: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SignerReport {
+    pub pubkey: String,
+    pub lamports: Option<u64>,
+    pub min_lamports: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BufferReport {
+    pub lamports: u64,
+    pub available_lamports: u64,
+    pub target_lamports: u64,
+    pub band_lamports: u64,
+    /// Rebalancing leaves the buffer where it is
+    pub holding: bool,
+}
+
+/// Start the staleness clock, ticks are measured against this until the first one finishes
+pub fn start() {
+    Lazy::force(&STATE);
+}
+
+fn state() -> std::sync::MutexGuard<'static, TickState> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Record the end of a tick, `ok` when every step of it succeeded
+pub fn tick_finished(ok: bool) {
+    let now = unix_now();
+    let mut state = state();
+    state.last_tick = Some(now);
+    if ok {
+        state.last_successful_tick = Some(now);
+    }
+}
+
+pub fn record_fill(signature: &str) {
+    state().last_fill = Some(Fill {
+        timestamp: unix_now(),
+        signature: signature.to_string(),
+    });
+}
+
+pub fn observe_vault(vault: &memepool::accounts::Vault) {
+    state().vault = Some((vault.lamports, vault.available_lamports));
+}
+
+/// Publish the target the liquidity buffer rebalances the observed vault toward
+pub fn observe_buffer(plan: &Plan) {
+    state().buffer = Some(*plan);
+}
+
+pub fn observe_withdraw_queue(pending: usize) {
+    state().pending_withdraw_requests = Some(pending);
+}
+
+/// Liveness: the main loop is still finishing ticks
+pub async fn healthz(State(probe): State<Probe>) -> (StatusCode, Json<Report>) {
+    let state = state().clone();
+    let mut report = report(&state);
+    if is_stale(&state, unix_now(), probe.max_tick_age_secs) {
+        report.failing.push("tick");
+    }
+    respond(report)
+}
+
+/// Readiness: live, RPC reachable, signer funded and the liquidity buffer within bounds
+pub async fn readyz(State(probe): State<Probe>) -> (StatusCode, Json<Report>) {
+    let state = state().clone();
+    let mut report = report(&state);
+    if is_stale(&state, unix_now(), probe.max_tick_age_secs) {
+        report.failing.push("tick");
+    }
+
+    let rpc = match probe.rpc.get_slot().await {
+        Ok(slot) => RpcReport {
+            reachable: true,
+            slot: Some(slot),
+            error: None,
+        },
+        Err(e) => RpcReport {
+            reachable: false,
+            slot: None,
+            error: Some(e.to_string()),
+        },
+    };
+    if !rpc.reachable {
+        report.failing.push("rpc");
+    }
+    report.rpc = Some(rpc);
+
+    let lamports = probe.rpc.get_balance(&probe.signer).await.ok();
+    if lamports.is_none_or(|lamports| lamports < probe.config.min_signer_lamports) {
+        report.failing.push("signer_balance");
+    }
+    report.signer = Some(SignerReport {
+        pubkey: probe.signer.to_string(),
+        lamports,
+        min_lamports: probe.config.min_signer_lamports,
+    });
+
+    match state.vault.zip(state.buffer) {
+        Some(((lamports, available_lamports), plan)) => {
+            if !buffer_ready(lamports, available_lamports, &plan) {
+                report.failing.push("liquidity_buffer");
+            }
+            report.liquidity_buffer = Some(BufferReport {
+                lamports,
+                available_lamports,
+                target_lamports: plan.target_lamports,
+                band_lamports: plan.band_lamports,
+                holding: plan.rebalance == Rebalance::Hold,
+            });
+        }
+        None => report.failing.push("liquidity_buffer"),
+    }
+
+    respond(report)
+}
+
+fn report(state: &TickState) -> Report {
+    Report {
+        ok: true,
+        failing: Vec::new(),
+        last_tick: state.last_tick,
+        last_successful_tick: state.last_successful_tick,
+        last_fill: state.last_fill.clone(),
+        pending_withdraw_requests: state.pending_withdraw_requests,
+        rpc: None,
+        signer: None,
+        liquidity_buffer: None,
+    }
+}
+
+fn respond(mut report: Report) -> (StatusCode, Json<Report>) {
+    report.ok = report.failing.is_empty();
+    let status = if report.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+/// No tick finished within `max_age` seconds, counting from process start before the first
+fn is_stale(state: &TickState, now: u64, max_age: u64) -> bool {
+    let last = state.last_tick.unwrap_or(state.started);
+    now.saturating_sub(last) > max_age
+}
+
+/// `available_lamports` is within the plan's target ± band, or as close as rebalancing gets it
+/// when the gap is smaller than a chunk. An empty vault has nothing to keep liquid.
+fn buffer_ready(lamports: u64, available_lamports: u64, plan: &Plan) -> bool {
+    lamports == 0
+        || available_lamports.abs_diff(plan.target_lamports) <= plan.band_lamports
+        || plan.rebalance == Rebalance::Hold
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_counts_from_start_until_the_first_tick() {
+        let mut state = TickState {
+            started: 1_000,
+            ..TickState::default()
+        };
+        assert!(!is_stale(&state, 1_100, 120));
+        assert!(is_stale(&state, 1_121, 120));
+
+        state.last_tick = Some(1_100);
+        assert!(!is_stale(&state, 1_220, 120));
+        assert!(is_stale(&state, 1_221, 120));
+    }
+
+    #[test]
+    fn buffer_is_ready_around_its_current_target() {
+        // A target raised by recent fills, 250 ± 20
+        let plan = Plan {
+            target_lamports: 250,
+            band_lamports: 20,
+            rebalance: Rebalance::Recall(150),
+        };
+        assert!(!buffer_ready(1_000, 100, &plan));
+        assert!(buffer_ready(1_000, 230, &plan));
+        assert!(buffer_ready(1_000, 270, &plan));
+        assert!(buffer_ready(0, 0, &plan));
+
+        // A gap smaller than a chunk is held, not closed
+        let plan = Plan {
+            rebalance: Rebalance::Hold,
+            ..plan
+        };
+        assert!(buffer_ready(1_000, 240, &plan));
+        assert!(buffer_ready(1_000, 200, &plan));
+    }
+}
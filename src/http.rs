@@ -0,0 +1,29 @@
+use std::net::SocketAddr;
+
+use axum::{routing::get, Router};
+use tracing::{error, info};
+
+use crate::{health, metrics};
+
+/// Serve `/metrics`, `/healthz` and `/readyz` on `listen` in the background
+pub async fn serve(listen: SocketAddr, probe: health::Probe) -> Result<(), String> {
+    let listener = tokio::net::TcpListener::bind(listen)
+        .await
+        .map_err(|e| format!("Failed to bind HTTP endpoint {}: {}", listen, e))?;
+    metrics::enable(listen);
+    health::start();
+
+    let app = Router::new()
+        .route("/metrics", get(metrics::render))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .with_state(probe);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!(error = %e, "HTTP endpoint stopped");
+        }
+    });
+
+    info!(%listen, "Serving metrics and health checks");
+    Ok(())
+}
@@ -0,0 +1,498 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anchor_client::{
+    solana_client::nonblocking::rpc_client::RpcClient,
+    solana_sdk::{commitment_config::CommitmentConfig, hash::Hash, signature::Signature},
+    Program,
+};
+use anchor_lang::prelude::Pubkey;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+
+use crate::{config, error::AggregatorError, memepool, signer::AggregatorSigner, utils::VAULT_PDA};
+
+static JOURNAL: OnceCell<Journal> = OnceCell::new();
+
+/// Append-only JSONL file, each line is the latest snapshot of one record
+struct Journal {
+    file: Mutex<File>,
+    next_id: AtomicU64,
+}
+
+/// One aggregator action, rewritten to the journal every time it changes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub id: u64,
+    /// Unix seconds of the last update
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub action: Action,
+    #[serde(flatten)]
+    pub status: Status,
+    /// Every signature sent for the action, oldest first
+    pub signatures: Vec<String>,
+    /// Blockhash the latest signature was signed against, earlier ones expired before it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blockhash: Option<String>,
+    pub vault_before: Option<VaultBalances>,
+    pub vault_after: Option<VaultBalances>,
+}
+
+/// Inputs and computed bounds of an action
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    Fill {
+        #[serde(with = "as_string")]
+        request: Pubkey,
+        #[serde(with = "as_string")]
+        withdrawer: Pubkey,
+        meme_amount: u64,
+        fill_lamports: u64,
+    },
+    /// WSOL swapped into token1 and deposited in the same transaction
+    LpDeposit {
+        #[serde(with = "as_string")]
+        pool: Pubkey,
+        deposit_amount: u64,
+        swap_amount: u64,
+        minimum_swap_out: u64,
+        lp_token_amount: u64,
+        maximum_token_0: u64,
+        maximum_token_1: u64,
+    },
+    /// LP burned and the token1 payout swapped back in the same transaction
+    LpWithdraw {
+        #[serde(with = "as_string")]
+        pool: Pubkey,
+        target_wsol: u64,
+        lp_token_amount: u64,
+        minimum_token_0: u64,
+        minimum_token_1: u64,
+        swap_amount: u64,
+        minimum_swap_out: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Status {
+    /// Signed and possibly sent, outcome unknown
+    Pending,
+    Landed {
+        signature: String,
+    },
+    Failed {
+        error: String,
+    },
+    /// Found pending on restart with none of its signatures ever seen by the cluster and its
+    /// blockhash expired
+    Dropped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VaultBalances {
+    pub lamports: u64,
+    pub available_lamports: u64,
+}
+
+impl Record {
+    pub fn add_signature(&mut self, signature: &Signature, blockhash: &Hash) {
+        self.signatures.push(signature.to_string());
+        self.blockhash = Some(blockhash.to_string());
+        write(self);
+    }
+
+    pub fn finish(
+        &mut self,
+        result: &Result<String, AggregatorError>,
+        vault_after: Option<VaultBalances>,
+    ) {
+        self.status = match result {
+            Ok(signature) => Status::Landed {
+                signature: signature.clone(),
+            },
+            Err(e) => Status::Failed {
+                error: e.to_string(),
+            },
+        };
+        self.vault_after = vault_after;
+        write(self);
+    }
+}
+
+/// Open (or create) the journal at `path` and return the records still pending from a
+/// previous run
+pub fn open(path: &Path) -> Result<Vec<Record>, String> {
+    let records = load(path)?;
+    let next_id = records.last().map_or(0, |record| record.id + 1);
+
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open journal {}: {}", path.display(), e))?;
+
+    JOURNAL
+        .set(Journal {
+            file: Mutex::new(file),
+            next_id: AtomicU64::new(next_id),
+        })
+        .map_err(|_| "Journal must only be opened once".to_string())?;
+
+    Ok(records
+        .into_iter()
+        .filter(|record| record.status == Status::Pending)
+        .collect())
+}
+
+/// Latest snapshot of every record in the journal, ordered by id. A last line left half
+/// written by a crash mid-append is cut off, so the next append starts on a line of its own.
+fn load(path: &Path) -> Result<Vec<Record>, String> {
+    let (records, partial) = parse(path)?;
+    if let Some(offset) = partial {
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_len(offset))
+            .map_err(|e| format!("Failed to truncate journal {}: {}", path.display(), e))?;
+    }
+    Ok(records)
+}
+
+/// Records in the journal along with the byte offset of a torn last line, if any. Only a last
+/// line cut off before its newline may fail to parse, any complete line that doesn't is
+/// corruption.
+fn parse(path: &Path) -> Result<(Vec<Record>, Option<u64>), String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), None)),
+        Err(e) => return Err(format!("Failed to read journal {}: {}", path.display(), e)),
+    };
+
+    let mut records = BTreeMap::new();
+    let mut offset = 0;
+    for (number, line) in contents.split_inclusive('\n').enumerate() {
+        let start = offset;
+        offset += line.len();
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Record>(line) {
+            Ok(record) => {
+                records.insert(record.id, record);
+            }
+            Err(e) if !line.ends_with('\n') => {
+                warn!(
+                    path = %path.display(),
+                    line = number + 1,
+                    error = %e,
+                    "Dropping a partially written last journal line"
+                );
+                return Ok((records.into_values().collect(), Some(start as u64)));
+            }
+            Err(e) => {
+                return Err(format!(
+                    "Failed to parse journal {} line {}: {}",
+                    path.display(),
+                    number + 1,
+                    e
+                ))
+            }
+        }
+    }
+
+    Ok((records.into_values().collect(), None))
+}
+
+pub fn is_open() -> bool {
+    JOURNAL.get().is_some()
+}
+
+/// Start a pending record for `action`, `None` when no journal is open (e.g. dry runs)
+pub fn begin(action: Action, vault_before: Option<VaultBalances>) -> Option<Record> {
+    let journal = JOURNAL.get()?;
+    let record = Record {
+        id: journal.next_id.fetch_add(1, Ordering::Relaxed),
+        timestamp: 0,
+        action,
+        status: Status::Pending,
+        signatures: Vec::new(),
+        blockhash: None,
+        vault_before,
+        vault_after: None,
+    };
+    write(&record);
+    Some(record)
+}
+
+/// Append the current snapshot of `record`. A journal failure is reported but never stops
+/// the aggregator.
+fn write(record: &Record) {
+    let Some(journal) = JOURNAL.get() else {
+        return;
+    };
+
+    let mut record = record.clone();
+    record.timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+
+    let result = serde_json::to_string(&record)
+        .map_err(|e| e.to_string())
+        .and_then(|line| {
+            let mut file = journal.file.lock().unwrap_or_else(|e| e.into_inner());
+            writeln!(file, "{}", line)
+                .and_then(|_| file.flush())
+                .map_err(|e| e.to_string())
+        });
+
+    if let Err(e) = result {
+        error!(record = record.id, error = %e, "Failed to write journal record");
+    }
+}
+
+/// Settle records left pending by a previous run from their signature statuses. A record
+/// none of whose signatures the cluster has seen is only dropped once its blockhash has
+/// expired, until then it may still land.
+pub async fn reconcile(rpc: &RpcClient, in_flight: Vec<Record>) -> Result<(), AggregatorError> {
+    let poll_interval = Duration::from_millis(config::get().rpc.confirm_poll_interval_ms);
+    for mut record in in_flight {
+        let signatures: Vec<Signature> = record
+            .signatures
+            .iter()
+            .filter_map(|signature| Signature::from_str(signature).ok())
+            .collect();
+        let blockhash = record
+            .blockhash
+            .as_deref()
+            .and_then(|blockhash| Hash::from_str(blockhash).ok());
+
+        record.status = loop {
+            if let Some(status) = signature_status(rpc, &signatures).await? {
+                break status;
+            }
+            match blockhash {
+                Some(blockhash) if blockhash_valid(rpc, &blockhash).await? => {
+                    info!(
+                        record = record.id,
+                        "Journal record may still land, waiting for its blockhash to expire"
+                    );
+                    sleep(poll_interval).await;
+                }
+                _ => break Status::Dropped,
+            }
+        };
+
+        info!(
+            record = record.id,
+            action = ?record.action,
+            status = ?record.status,
+            "Reconciled journal record"
+        );
+        write(&record);
+    }
+
+    Ok(())
+}
+
+/// Outcome of the first of `signatures` the cluster has seen, `None` if it has seen none
+async fn signature_status(
+    rpc: &RpcClient,
+    signatures: &[Signature],
+) -> Result<Option<Status>, AggregatorError> {
+    if signatures.is_empty() {
+        return Ok(None);
+    }
+    let statuses = rpc
+        .get_signature_statuses_with_history(signatures)
+        .await
+        .map_err(|e| AggregatorError::Rpc {
+            context: "Failed to get signature statuses",
+            message: e.to_string(),
+        })?
+        .value;
+
+    Ok(signatures
+        .iter()
+        .zip(statuses)
+        .find_map(|(signature, status)| {
+            status.map(|status| match status.err {
+                Some(err) => Status::Failed {
+                    error: err.to_string(),
+                },
+                None => Status::Landed {
+                    signature: signature.to_string(),
+                },
+            })
+        }))
+}
+
+async fn blockhash_valid(rpc: &RpcClient, blockhash: &Hash) -> Result<bool, AggregatorError> {
+    rpc.is_blockhash_valid(blockhash, CommitmentConfig::processed())
+        .await
+        .map_err(|e| AggregatorError::Rpc {
+            context: "Failed to check blockhash validity",
+            message: e.to_string(),
+        })
+}
+
+/// Vault lamports to record around an action, `None` if the vault can't be read
+pub async fn vault_balances(program: &Program<Arc<AggregatorSigner>>) -> Option<VaultBalances> {
+    program
+        .account::<memepool::accounts::Vault>(*VAULT_PDA)
+        .await
+        .ok()
+        .map(|vault| VaultBalances {
+            lamports: vault.lamports,
+            available_lamports: vault.available_lamports,
+        })
+}
+
+/// Serialize through `Display`/`FromStr`, so pubkeys read as base58 in the journal
+mod as_string {
+    use std::{fmt::Display, str::FromStr};
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer, T: Display>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr,
+        T::Err: Display,
+    {
+        let s = String::deserialize(deserializer)?;
+        T::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: u64, status: Status) -> Record {
+        Record {
+            id,
+            timestamp: 1_700_000_000,
+            action: Action::Fill {
+                request: Pubkey::new_unique(),
+                withdrawer: Pubkey::new_unique(),
+                meme_amount: 1_000,
+                fill_lamports: 5_000,
+            },
+            status,
+            signatures: vec![Signature::default().to_string()],
+            blockhash: Some(Hash::default().to_string()),
+            vault_before: Some(VaultBalances {
+                lamports: 10_000,
+                available_lamports: 8_000,
+            }),
+            vault_after: None,
+        }
+    }
+
+    #[test]
+    fn record_round_trips_as_flat_json() {
+        let record = record(
+            3,
+            Status::Failed {
+                error: "Slippage exceeded".to_string(),
+            },
+        );
+        let line = serde_json::to_string(&record).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["action"], "fill");
+        assert_eq!(json["status"], "failed");
+        assert_eq!(json["meme_amount"], 1_000);
+        assert!(json["request"].is_string());
+        assert_eq!(serde_json::from_str::<Record>(&line).unwrap(), record);
+    }
+
+    #[test]
+    fn load_keeps_latest_snapshot_per_id() {
+        let path = std::env::temp_dir().join(format!(
+            "aggregator-journal-test-{}.jsonl",
+            std::process::id()
+        ));
+        let first = record(0, Status::Pending);
+        let second = record(1, Status::Pending);
+        let mut landed = first.clone();
+        landed.status = Status::Landed {
+            signature: Signature::default().to_string(),
+        };
+
+        let lines = [&first, &second, &landed]
+            .iter()
+            .map(|record| serde_json::to_string(record).unwrap())
+            .collect::<Vec<_>>()
+            .join("\n");
+        std::fs::write(&path, lines).unwrap();
+
+        let records = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records, vec![landed, second]);
+    }
+
+    #[test]
+    fn partial_last_line_is_dropped_and_truncated() {
+        let path = std::env::temp_dir().join(format!(
+            "aggregator-journal-test-partial-{}.jsonl",
+            std::process::id()
+        ));
+        let first = record(0, Status::Pending);
+        let second = serde_json::to_string(&record(1, Status::Pending)).unwrap();
+        let contents = format!(
+            "{}\n{}",
+            serde_json::to_string(&first).unwrap(),
+            &second[..second.len() / 2]
+        );
+        std::fs::write(&path, &contents).unwrap();
+
+        assert_eq!(load(&path).unwrap(), vec![first.clone()]);
+        // The next append lands on a clean line
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "{}", second).unwrap();
+        let records = load(&path).unwrap();
+
+        // Corruption before the last line still fails, and so does a complete last line
+        std::fs::write(&path, format!("{{\n{}\n", second)).unwrap();
+        let corrupt = load(&path);
+        let complete = format!("{}\n{{\n", second);
+        std::fs::write(&path, &complete).unwrap();
+        let corrupt_last = load(&path);
+        let untouched = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0], first);
+        assert!(corrupt.is_err());
+        assert!(corrupt_last.is_err());
+        assert_eq!(untouched, complete);
+    }
+
+    #[test]
+    fn missing_journal_is_empty() {
+        let path = std::env::temp_dir().join("aggregator-journal-test-missing.jsonl");
+        assert_eq!(load(&path).unwrap(), Vec::new());
+    }
+}
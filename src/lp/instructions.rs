@@ -1,42 +1,256 @@
 use anchor_client::{
-    solana_sdk::{signature::Keypair, signer::Signer, system_program},
+    solana_sdk::{
+        instruction::Instruction, signature::Signature, signer::Signer, system_program,
+        transaction::Transaction,
+    },
     Program,
 };
-
+use anchor_lang::{prelude::Pubkey, InstructionData, ToAccountMetas};
 use anchor_spl::token::spl_token;
-use std::rc::Rc;
+use std::{sync::Arc, time::Instant};
+use tracing::{debug, error, info, warn, Span};
 
 use crate::{
-    memepool,
-    raydium::get_pool_state,
+    client, config,
+    error::AggregatorError,
+    fees,
+    journal::{self, Action, Record},
+    memepool, metrics,
+    raydium::PoolState,
+    signer::AggregatorSigner,
+    simulation,
+    submitter::{RetryGuard, Submission, Submitter},
     utils::{
-        get_oracle_pda, get_vault_pool_pda, CP_SWAP_PROGRAM, MEMO_PROGRAM, POOL_ADDRESS,
-        SWAP_AUTHORITY_PDA, VAULT_PDA,
+        get_oracle_pda, get_vault_pool_pda, CP_SWAP_PROGRAM, MEMO_PROGRAM, SWAP_AUTHORITY_PDA,
+        VAULT_PDA,
     },
 };
 
-pub async fn lp_swap(
-    program: &Program<Rc<Keypair>>,
-    raydium_program: &Program<Rc<Keypair>>,
-    aggregator_keypair: &Keypair,
+/// Fallback compute units per memepool instruction when simulation doesn't report usage
+pub const LP_SWAP_COMPUTE_UNITS: u32 = 120_000;
+pub const LP_DEPOSIT_COMPUTE_UNITS: u32 = 150_000;
+pub const LP_WITHDRAW_COMPUTE_UNITS: u32 = 150_000;
+
+/// Collects memepool instructions and sends them as a single transaction, so a failure in any
+/// step reverts all of them
+pub struct TxComposer<'a> {
+    program: &'a Program<Arc<AggregatorSigner>>,
+    signer: &'a AggregatorSigner,
+    instructions: Vec<Instruction>,
+    compute_units: u32,
+    retry_guard: Option<RetryGuard>,
+    action: Option<Action>,
+}
+
+impl<'a> TxComposer<'a> {
+    pub fn new(program: &'a Program<Arc<AggregatorSigner>>, signer: &'a AggregatorSigner) -> Self {
+        Self {
+            program,
+            signer,
+            instructions: Vec::new(),
+            compute_units: 0,
+            retry_guard: None,
+            action: None,
+        }
+    }
+
+    /// Append an instruction along with the compute units it is expected to use, the
+    /// estimate only applies when simulation doesn't report the units consumed
+    pub fn push(mut self, instruction: Instruction, compute_units: u32) -> Self {
+        self.instructions.push(instruction);
+        self.compute_units = self.compute_units.saturating_add(compute_units);
+        self
+    }
+
+    /// Skip re-signing once `guard` no longer holds, the first attempt is always sent
+    pub fn retry_guard(mut self, guard: RetryGuard) -> Self {
+        self.retry_guard = Some(guard);
+        self
+    }
+
+    /// Record the send, its signatures and outcome in the journal as `action`
+    pub fn journal(mut self, action: Action) -> Self {
+        self.action = Some(action);
+        self
+    }
+
+    /// Send with a simulated compute unit limit and a priority fee, then track the signature
+    /// until it lands. Expired attempts are re-signed with a fresh blockhash and an escalated
+    /// fee, up to `fees.max_attempts` times.
+    ///
+    /// The landed signature, or the program logs of a failed transaction, are recorded on
+    /// the current span's `signature` and `program_logs` fields.
+    pub async fn send(mut self, context: &'static str) -> Result<String, AggregatorError> {
+        let action = self.action.take();
+        let mut record = match &action {
+            Some(action) if journal::is_open() => {
+                journal::begin(action.clone(), journal::vault_balances(self.program).await)
+            }
+            _ => None,
+        };
+
+        let started = Instant::now();
+        let result = self.send_attempts(record.as_mut(), context).await;
+        metrics::record_transaction(action.as_ref(), &result, started.elapsed());
+
+        let span = Span::current();
+        match &result {
+            Ok(signature) => {
+                span.record("signature", signature.as_str());
+            }
+            Err(err) => {
+                span.record("program_logs", tracing::field::debug(err.logs()));
+                error!(error = %err, program_logs = ?err.logs(), "Transaction failed");
+            }
+        }
+
+        if let Some(record) = record.as_mut() {
+            record.finish(&result, journal::vault_balances(self.program).await);
+        }
+        result
+    }
+
+    async fn send_attempts(
+        &self,
+        mut record: Option<&mut Record>,
+        context: &'static str,
+    ) -> Result<String, AggregatorError> {
+        let config = config::get();
+        let rpc = client::rpc();
+        let payer = self.signer.pubkey();
+        let mut submitter = Submitter::new(rpc, &config.rpc);
+
+        let mut attempt = 0;
+        loop {
+            if let Some(guard) = self.retry_guard.as_ref().filter(|_| attempt > 0) {
+                if !guard.holds(rpc).await? {
+                    return Err(AggregatorError::RetryAborted(format!(
+                        "{} changed after sending {}",
+                        guard.account,
+                        join_signatures(submitter.signatures())
+                    )));
+                }
+            }
+
+            let budget = fees::compute_budget(
+                rpc,
+                &payer,
+                &self.instructions,
+                self.compute_units,
+                attempt,
+                &config.fees,
+                context,
+            )
+            .await?;
+
+            info!(
+                unit_limit = budget.unit_limit,
+                unit_price = budget.unit_price,
+                priority_fee_lamports = budget.priority_fee_lamports(),
+                "Compute budget"
+            );
+
+            let instructions: Vec<Instruction> = budget
+                .instructions()
+                .into_iter()
+                .chain(self.instructions.iter().cloned())
+                .collect();
+
+            if config.aggregator.dry_run {
+                return simulation::dry_run(rpc, &payer, &instructions, context).await;
+            }
+
+            // Signs against the latest blockhash
+            let blockhash = rpc
+                .get_latest_blockhash()
+                .await
+                .map_err(|e| AggregatorError::Rpc {
+                    context: "Failed to get latest blockhash",
+                    message: e.to_string(),
+                })?;
+            let mut tx = Transaction::new_with_payer(&instructions, Some(&payer));
+            tx.try_sign(&[self.signer], blockhash)
+                .map_err(|e| AggregatorError::Rpc {
+                    context: "Failed to sign transaction",
+                    message: e.to_string(),
+                })?;
+
+            if let Some(record) = record.as_deref_mut() {
+                record.add_signature(&tx.signatures[0], &blockhash);
+            }
+
+            match submitter.submit(&tx, context).await? {
+                Submission::Landed(signature) => return Ok(signature.to_string()),
+                Submission::Expired => {}
+            }
+
+            attempt += 1;
+            if attempt >= config.fees.max_attempts {
+                return Err(AggregatorError::Rpc {
+                    context,
+                    message: format!(
+                        "not confirmed after {} attempts ({})",
+                        attempt,
+                        join_signatures(submitter.signatures())
+                    ),
+                });
+            }
+            warn!(
+                attempt = attempt + 1,
+                max_attempts = config.fees.max_attempts,
+                "Blockhash expired, re-signing"
+            );
+        }
+    }
+}
+
+fn join_signatures(signatures: &[Signature]) -> String {
+    signatures
+        .iter()
+        .map(Signature::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn memepool_instruction(
+    accounts: impl ToAccountMetas,
+    args: impl InstructionData,
+) -> Instruction {
+    Instruction {
+        program_id: memepool::ID,
+        accounts: accounts.to_account_metas(None),
+        data: args.data(),
+    }
+}
+
+/// The vault's ATA for `mint`, derived under the token program that owns the mint
+fn vault_token_account(mint: &Pubkey, token_program: &Pubkey) -> Pubkey {
+    anchor_spl::associated_token::get_associated_token_address_with_program_id(
+        &VAULT_PDA,
+        mint,
+        token_program,
+    )
+}
+
+pub fn lp_swap(
+    aggregator_signer: &AggregatorSigner,
+    pool_address: Pubkey,
+    pool_state: &PoolState,
     amount_in: u64,
     minimum_amount_out: u64,
     is_base_token: bool, // true: swap WSOL to test token, false: swap test token to WSOL
-) -> Result<String, String> {
+) -> Instruction {
     let vault_address = *VAULT_PDA;
-    let cp_swap_program = CP_SWAP_PROGRAM;
-    let pool_address = POOL_ADDRESS;
-
-    // TODO: pass in pool state
-    let pool_state = get_pool_state(raydium_program, pool_address)
-        .await
-        .map_err(|e| format!("Failed to get pool state: {}", e))?;
+    let cp_swap_program = *CP_SWAP_PROGRAM;
 
     let config_id = pool_state.amm_config;
     let vault_a = pool_state.token_0_vault;
     let vault_b = pool_state.token_1_vault;
     let mint_a = pool_state.token_0_mint;
     let mint_b = pool_state.token_1_mint;
+    // Either side may be a Token-2022 mint
+    let program_a = pool_state.token_0_program;
+    let program_b = pool_state.token_1_program;
 
     // Get authority PDA for the swap program
     let authority = *SWAP_AUTHORITY_PDA;
@@ -49,23 +263,29 @@ pub async fn lp_swap(
         output_vault,
         input_token_mint,
         output_token_mint,
+        input_token_program,
+        output_token_program,
     ) = if is_base_token {
         (
-            anchor_spl::associated_token::get_associated_token_address(&vault_address, &mint_a),
-            anchor_spl::associated_token::get_associated_token_address(&vault_address, &mint_b),
+            vault_token_account(&mint_a, &program_a),
+            vault_token_account(&mint_b, &program_b),
             vault_a,
             vault_b,
             mint_a,
             mint_b,
+            program_a,
+            program_b,
         )
     } else {
         (
-            anchor_spl::associated_token::get_associated_token_address(&vault_address, &mint_b),
-            anchor_spl::associated_token::get_associated_token_address(&vault_address, &mint_a),
+            vault_token_account(&mint_b, &program_b),
+            vault_token_account(&mint_a, &program_a),
             vault_b,
             vault_a,
             mint_b,
             mint_a,
+            program_b,
+            program_a,
         )
     };
 
@@ -73,7 +293,7 @@ pub async fn lp_swap(
     let observation_address = get_oracle_pda(&pool_address);
 
     let accounts = memepool::client::accounts::LpSwap {
-        aggregator: aggregator_keypair.pubkey(),
+        aggregator: aggregator_signer.pubkey(),
         vault: vault_address,
         cp_swap_program,
         authority,
@@ -83,8 +303,8 @@ pub async fn lp_swap(
         output_token_account,
         input_vault,
         output_vault,
-        input_token_program: spl_token::ID,
-        output_token_program: spl_token::ID,
+        input_token_program,
+        output_token_program,
         input_token_mint,
         output_token_mint,
         observation_state: observation_address,
@@ -95,50 +315,22 @@ pub async fn lp_swap(
         minimum_amount_out,
     };
 
-    let tx_builder = program.request().args(args).accounts(accounts);
-
-    let tx = match tx_builder.send().await {
-        Ok(sig) => Ok(sig.to_string()),
-        Err(e) => {
-            println!("\nTransaction failed with error:");
-            // println!("{:#?}", e);
-
-            // TODO: TEMP TO GET PROGRAM LOGS
-            if let anchor_client::ClientError::ProgramError(program_err) = &e {
-                println!("\nProgram error details:");
-                println!("Error code: {}", program_err.to_string());
-            } else if let anchor_client::ClientError::SolanaClientError(rpc_err) = &e {
-                println!("\nRPC error details:");
-                println!("{:#?}", rpc_err);
-            }
-
-            Err(format!("Failed to send swap transaction: {}", e))
-        }
-    }?;
-
-    Ok(tx.to_string())
+    memepool_instruction(accounts, args)
 }
 
-pub async fn lp_deposit(
-    program: &Program<Rc<Keypair>>,
-    raydium_program: &Program<Rc<Keypair>>,
-    aggregator_keypair: &Keypair,
+pub fn lp_deposit(
+    aggregator_signer: &AggregatorSigner,
+    pool_address: Pubkey,
+    pool_state: &PoolState,
     lp_token_amount: u64,
     maximum_token_0_amount: u64,
     maximum_token_1_amount: u64,
-) -> Result<String, String> {
+) -> Instruction {
     let vault_address = *VAULT_PDA;
-    let cp_swap_program = CP_SWAP_PROGRAM;
-    let pool_address = POOL_ADDRESS;
-
+    let cp_swap_program = *CP_SWAP_PROGRAM;
     let vault_pool_address = get_vault_pool_pda(&pool_address);
 
-    println!("Using vault pool address: {}", vault_pool_address);
-
-    // TODO: pass in pool state
-    let pool_state = get_pool_state(raydium_program, pool_address)
-        .await
-        .map_err(|e| format!("Failed to get pool state: {}", e))?;
+    debug!(%vault_pool_address, "Using vault pool address");
 
     let vault_a = pool_state.token_0_vault;
     let vault_b = pool_state.token_1_vault;
@@ -150,15 +342,13 @@ pub async fn lp_deposit(
 
     let lp_mint = pool_state.lp_mint;
 
-    let owner_lp_token =
-        anchor_spl::associated_token::get_associated_token_address(&vault_address, &lp_mint);
-    let owner_token_0 =
-        anchor_spl::associated_token::get_associated_token_address(&vault_address, &mint_a);
-    let owner_token_1 =
-        anchor_spl::associated_token::get_associated_token_address(&vault_address, &mint_b);
+    // LP mints are legacy SPL tokens, either pool token may be a Token-2022 mint
+    let owner_lp_token = vault_token_account(&lp_mint, &spl_token::ID);
+    let owner_token_0 = vault_token_account(&mint_a, &pool_state.token_0_program);
+    let owner_token_1 = vault_token_account(&mint_b, &pool_state.token_1_program);
 
     let accounts = memepool::client::accounts::LpDeposit {
-        aggregator: aggregator_keypair.pubkey(),
+        aggregator: aggregator_signer.pubkey(),
         vault: vault_address,
         vault_pool: vault_pool_address,
         cp_swap_program,
@@ -184,35 +374,22 @@ pub async fn lp_deposit(
         maximum_token_1_amount,
     };
 
-    let tx_builder = program.request().args(args).accounts(accounts);
-
-    let tx = tx_builder
-        .send()
-        .await
-        .map_err(|e| format!("Failed to send lp deposit transaction: {}", e))?;
-
-    Ok(tx.to_string())
+    memepool_instruction(accounts, args)
 }
 
-pub async fn lp_withdraw(
-    program: &Program<Rc<Keypair>>,
-    raydium_program: &Program<Rc<Keypair>>,
-    aggregator_keypair: &Keypair,
+pub fn lp_withdraw(
+    aggregator_signer: &AggregatorSigner,
+    pool_address: Pubkey,
+    pool_state: &PoolState,
     lp_token_amount: u64,
     minimum_token_0_amount: u64,
     minimum_token_1_amount: u64,
-) -> Result<String, String> {
+) -> Instruction {
     let vault_address = *VAULT_PDA;
-    let cp_swap_program = CP_SWAP_PROGRAM;
-    let pool_address = POOL_ADDRESS;
+    let cp_swap_program = *CP_SWAP_PROGRAM;
     let vault_pool_address = get_vault_pool_pda(&pool_address);
 
-    println!("Using vault pool address: {}", vault_pool_address);
-
-    // TODO: pass in pool state
-    let pool_state = get_pool_state(raydium_program, pool_address)
-        .await
-        .map_err(|e| format!("Failed to get pool state: {}", e))?;
+    debug!(%vault_pool_address, "Using vault pool address");
 
     let vault_a = pool_state.token_0_vault;
     let vault_b = pool_state.token_1_vault;
@@ -224,15 +401,13 @@ pub async fn lp_withdraw(
 
     let lp_mint = pool_state.lp_mint;
 
-    let owner_lp_token =
-        anchor_spl::associated_token::get_associated_token_address(&vault_address, &lp_mint);
-    let owner_token_0 =
-        anchor_spl::associated_token::get_associated_token_address(&vault_address, &mint_a);
-    let owner_token_1 =
-        anchor_spl::associated_token::get_associated_token_address(&vault_address, &mint_b);
+    // LP mints are legacy SPL tokens, either pool token may be a Token-2022 mint
+    let owner_lp_token = vault_token_account(&lp_mint, &spl_token::ID);
+    let owner_token_0 = vault_token_account(&mint_a, &pool_state.token_0_program);
+    let owner_token_1 = vault_token_account(&mint_b, &pool_state.token_1_program);
 
     let accounts = memepool::client::accounts::LpWithdraw {
-        aggregator: aggregator_keypair.pubkey(),
+        aggregator: aggregator_signer.pubkey(),
         vault: vault_address,
         vault_pool: vault_pool_address,
         cp_swap_program,
@@ -248,7 +423,7 @@ pub async fn lp_withdraw(
         vault_0_mint: mint_a,
         vault_1_mint: mint_b,
         lp_mint,
-        memo_program: MEMO_PROGRAM,
+        memo_program: *MEMO_PROGRAM,
     };
 
     let args = memepool::client::args::LpWithdraw {
@@ -257,30 +432,5 @@ pub async fn lp_withdraw(
         minimum_token_1_amount,
     };
 
-    let tx_builder = program.request().args(args).accounts(accounts);
-
-    // let tx = tx_builder
-    //     .send()
-    //     .await
-    //     .map_err(|e| format!("Failed to send lp deposit transaction: {}", e))?;
-    let tx = match tx_builder.send().await {
-        Ok(sig) => Ok(sig.to_string()),
-        Err(e) => {
-            println!("\nTransaction failed with error:");
-            // println!("{:#?}", e);
-
-            // TODO: TEMP TO GET PROGRAM LOGS
-            if let anchor_client::ClientError::ProgramError(program_err) = &e {
-                println!("\nProgram error details:");
-                println!("Error code: {}", program_err.to_string());
-            } else if let anchor_client::ClientError::SolanaClientError(rpc_err) = &e {
-                println!("\nRPC error details:");
-                println!("{:#?}", rpc_err);
-            }
-
-            Err(format!("Failed to send swap transaction: {}", e))
-        }
-    }?;
-
-    Ok(tx.to_string())
+    memepool_instruction(accounts, args)
 }
@@ -1,6 +1,10 @@
 pub mod instructions;
+pub mod oracle;
+pub mod quote;
 pub mod service;
 pub mod utils;
+pub mod zap;
 
 pub use service::process_lp_deposit;
 pub use service::process_lp_withdraw;
+pub use service::vault_lp_position;
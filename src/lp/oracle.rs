@@ -0,0 +1,186 @@
+use std::sync::Arc;
+
+use anchor_client::Program;
+use anchor_lang::prelude::Pubkey;
+use tracing::{debug, warn};
+
+use crate::{
+    config,
+    error::AggregatorError,
+    raydium::{get_observation_state, Observation, PoolState, OBSERVATION_NUM},
+    signer::AggregatorSigner,
+};
+
+use super::quote::PoolQuoter;
+
+/// Raydium CPMM prices are Q32.32 fixed point
+const Q32: u32 = 32;
+
+/// Refuse to trade against `pool_address` while the spot price of `quoter`'s reserves is
+/// more than `max_deviation_bps` away from the pool's TWAP. Passes with a warning when the
+/// pool hasn't recorded enough observations to average over, i.e. no swaps yet.
+pub async fn check_price(
+    raydium_program: &Program<Arc<AggregatorSigner>>,
+    pool_address: Pubkey,
+    pool_state: &PoolState,
+    quoter: &PoolQuoter,
+) -> Result<(), AggregatorError> {
+    let oracle = config::get().oracle.clone();
+    let observation_state = get_observation_state(raydium_program, pool_state.observation_key)
+        .await
+        .map_err(|e| AggregatorError::from_client("Failed to get observation state", e))?;
+
+    let observations = observation_state.observations;
+    let Some(twap) = twap_x32(
+        &observations,
+        observation_state.observation_index as usize,
+        oracle.twap_window_secs,
+    ) else {
+        warn!(pool = %pool_address, "Pool has no TWAP yet, trading on spot alone");
+        return Ok(());
+    };
+    let Some(spot) = spot_x32(quoter.reserve_0, quoter.reserve_1) else {
+        return Err(AggregatorError::InsufficientLiquidity(
+            "cannot price a pool with an empty reserve".to_string(),
+        ));
+    };
+
+    let deviation_bps = deviation_bps(spot, twap);
+    debug!(
+        pool = %pool_address,
+        spot_x32 = spot,
+        twap_x32 = twap,
+        deviation_bps,
+        "Spot price against TWAP"
+    );
+    if deviation_bps > oracle.max_deviation_bps {
+        return Err(AggregatorError::PriceDeviation {
+            pool: pool_address,
+            deviation_bps,
+        });
+    }
+    Ok(())
+}
+
+/// Time-weighted average price of token1 in token0 (lamports per token1), Q32.32, from the
+/// newest observation back to the newest one at least `window_secs` older, or the oldest
+/// recorded when the ring doesn't reach that far. `None` with fewer than two observations.
+fn twap_x32(
+    observations: &[Observation; OBSERVATION_NUM],
+    index: usize,
+    window_secs: u64,
+) -> Option<u128> {
+    let newest = observations.get(index)?;
+    let newest_timestamp = newest.block_timestamp;
+    if newest_timestamp == 0 {
+        return None;
+    }
+
+    let mut start = None;
+    for back in 1..OBSERVATION_NUM {
+        let observation = &observations[(index + OBSERVATION_NUM - back) % OBSERVATION_NUM];
+        let timestamp = observation.block_timestamp;
+        // Unwritten slots are zeroed, anything not older wraps back past the newest
+        if timestamp == 0 || timestamp >= newest_timestamp {
+            break;
+        }
+        start = Some(observation);
+        if newest_timestamp - timestamp >= window_secs {
+            break;
+        }
+    }
+
+    let start = start?;
+    let elapsed = newest_timestamp - start.block_timestamp;
+    // Cumulative prices wrap, their difference doesn't as long as the window is sane
+    let cumulative = newest
+        .cumulative_token_1_price_x32
+        .wrapping_sub(start.cumulative_token_1_price_x32);
+    Some(cumulative / elapsed as u128)
+}
+
+/// Spot price of token1 in token0 from (WSOL, token1) reserves, Q32.32
+fn spot_x32(reserve_0: u64, reserve_1: u64) -> Option<u128> {
+    if reserve_1 == 0 {
+        return None;
+    }
+    Some(((reserve_0 as u128) << Q32) / reserve_1 as u128)
+}
+
+/// Distance of `spot` from `twap`, in bps of the TWAP
+fn deviation_bps(spot: u128, twap: u128) -> u64 {
+    if twap == 0 {
+        return u64::MAX;
+    }
+    let bps = spot.abs_diff(twap).saturating_mul(10_000) / twap;
+    bps.min(u64::MAX as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Observations every 15 seconds from `start` at a constant `price_x32`, newest at `index`
+    fn ring(
+        start: u64,
+        count: usize,
+        index: usize,
+        price_x32: u128,
+    ) -> [Observation; OBSERVATION_NUM] {
+        let mut observations = [Observation::default(); OBSERVATION_NUM];
+        for i in 0..count {
+            let slot = (index + OBSERVATION_NUM - (count - 1 - i)) % OBSERVATION_NUM;
+            observations[slot] = Observation {
+                block_timestamp: start + 15 * i as u64,
+                cumulative_token_0_price_x32: 0,
+                cumulative_token_1_price_x32: price_x32.wrapping_mul(15 * i as u128),
+            };
+        }
+        observations
+    }
+
+    #[test]
+    fn twap_averages_over_the_window() {
+        let price = 250u128 << Q32;
+        let observations = ring(1_000, 10, 4, price);
+        assert_eq!(twap_x32(&observations, 4, 60), Some(price));
+        // Window longer than the ring falls back to the oldest observation
+        assert_eq!(twap_x32(&observations, 4, 3_600), Some(price));
+
+        // A spike in the last 15 seconds moves the 60 second TWAP by a quarter of it
+        let mut spiked = observations;
+        spiked[4].cumulative_token_1_price_x32 =
+            spiked[3].cumulative_token_1_price_x32 + (1_250u128 << Q32) * 15;
+        assert_eq!(twap_x32(&spiked, 4, 60), Some(500u128 << Q32));
+    }
+
+    #[test]
+    fn twap_needs_two_observations() {
+        let observations = ring(1_000, 1, 0, 1 << Q32);
+        assert_eq!(twap_x32(&observations, 0, 60), None);
+        let observations = [Observation::default(); OBSERVATION_NUM];
+        assert_eq!(twap_x32(&observations, OBSERVATION_NUM - 1, 60), None);
+    }
+
+    #[test]
+    fn twap_survives_wrapping_cumulatives() {
+        let price = 7u128 << Q32;
+        let mut observations = ring(1_000, 5, 2, price);
+        for observation in observations.iter_mut().filter(|o| o.block_timestamp != 0) {
+            observation.cumulative_token_1_price_x32 = observation
+                .cumulative_token_1_price_x32
+                .wrapping_add(u128::MAX - 20);
+        }
+        assert_eq!(twap_x32(&observations, 2, 30), Some(price));
+    }
+
+    #[test]
+    fn deviation_is_relative_to_the_twap() {
+        let spot = spot_x32(1_000, 4_000).unwrap();
+        assert_eq!(spot, (1u128 << Q32) / 4);
+        assert_eq!(deviation_bps(spot, spot), 0);
+        assert_eq!(deviation_bps(10_300, 10_000), 300);
+        assert_eq!(deviation_bps(spot / 2, spot), 5_000);
+        assert_eq!(spot_x32(1_000, 0), None);
+    }
+}
@@ -0,0 +1,547 @@
+use std::sync::Arc;
+
+use anchor_client::Program;
+use anchor_lang::prelude::Pubkey;
+use anchor_spl::token_2022::{
+    self,
+    spl_token_2022::{
+        extension::{
+            transfer_fee::{self, TransferFeeConfig},
+            BaseStateWithExtensions, StateWithExtensions,
+        },
+        state::Mint,
+    },
+};
+
+use crate::{
+    client,
+    error::AggregatorError,
+    raydium::{get_amm_config, PoolState},
+    signer::AggregatorSigner,
+};
+
+/// Raydium CPMM fee rates are denominated in hundredths of a bip (10^-6)
+pub const FEE_RATE_DENOMINATOR: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwapQuote {
+    /// Total amount taken from the input side, trade fee included
+    pub amount_in: u64,
+    /// Amount arriving on the output side
+    pub amount_out: u64,
+    /// Part of `amount_in` kept by the pool as trade fee
+    pub trade_fee: u64,
+    /// Token-2022 transfer fees withheld from `amount_in` on its way into the pool and from
+    /// the pool's payout on its way to `amount_out`
+    pub transfer_fee_in: u64,
+    pub transfer_fee_out: u64,
+}
+
+/// Token-2022 transfer fee of a mint in the current epoch, `NONE` for legacy SPL mints and
+/// Token-2022 mints without the extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferFee {
+    pub basis_points: u16,
+    pub maximum_fee: u64,
+}
+
+impl TransferFee {
+    pub const NONE: Self = Self {
+        basis_points: 0,
+        maximum_fee: 0,
+    };
+
+    /// Load the fee `mint` charges in `epoch`, reading the mint only when it belongs to
+    /// Token-2022
+    async fn load(
+        mint: &Pubkey,
+        token_program: &Pubkey,
+        epoch: u64,
+    ) -> Result<Self, AggregatorError> {
+        if *token_program != token_2022::ID {
+            return Ok(Self::NONE);
+        }
+        let account = client::rpc()
+            .get_account(mint)
+            .await
+            .map_err(|e| AggregatorError::Rpc {
+                context: "Failed to get mint",
+                message: e.to_string(),
+            })?;
+        let mint_state = StateWithExtensions::<Mint>::unpack(&account.data).map_err(|e| {
+            AggregatorError::Rpc {
+                context: "Failed to decode Token-2022 mint",
+                message: e.to_string(),
+            }
+        })?;
+        Ok(mint_state
+            .get_extension::<TransferFeeConfig>()
+            .map_or(Self::NONE, |config| Self::from(config.get_epoch_fee(epoch))))
+    }
+
+    fn spl(&self) -> transfer_fee::TransferFee {
+        transfer_fee::TransferFee {
+            epoch: 0.into(),
+            maximum_fee: self.maximum_fee.into(),
+            transfer_fee_basis_points: self.basis_points.into(),
+        }
+    }
+
+    /// Fee withheld from a transfer of `amount`
+    pub fn fee(&self, amount: u64) -> Result<u64, AggregatorError> {
+        self.spl()
+            .calculate_fee(amount)
+            .ok_or(AggregatorError::MathOverflow("transfer fee"))
+    }
+
+    /// What arrives of a transfer of `amount`
+    pub fn post_fee_amount(&self, amount: u64) -> Result<u64, AggregatorError> {
+        self.spl()
+            .calculate_post_fee_amount(amount)
+            .ok_or(AggregatorError::MathOverflow("transfer post-fee amount"))
+    }
+
+    /// Fee on top of `post_fee_amount` so that exactly it arrives, the way the CPMM program
+    /// charges depositors
+    pub fn inverse_fee(&self, post_fee_amount: u64) -> Result<u64, AggregatorError> {
+        if self.basis_points == transfer_fee::MAX_FEE_BASIS_POINTS {
+            return Ok(self.maximum_fee);
+        }
+        self.spl()
+            .calculate_inverse_fee(post_fee_amount)
+            .ok_or(AggregatorError::MathOverflow("transfer inverse fee"))
+    }
+}
+
+impl From<&transfer_fee::TransferFee> for TransferFee {
+    fn from(fee: &transfer_fee::TransferFee) -> Self {
+        Self {
+            basis_points: fee.transfer_fee_basis_points.into(),
+            maximum_fee: fee.maximum_fee.into(),
+        }
+    }
+}
+
+/// Snapshot of a pool's swap reserves, trade fee rate and its mints' transfer fees
+#[derive(Debug, Clone, Copy)]
+pub struct PoolQuoter {
+    pub reserve_0: u64,
+    pub reserve_1: u64,
+    pub trade_fee_rate: u64,
+    pub transfer_fee_0: TransferFee,
+    pub transfer_fee_1: TransferFee,
+}
+
+impl PoolQuoter {
+    /// Load the pool's `AmmConfig`, current reserves and transfer fees
+    pub async fn load(
+        raydium_program: &Program<Arc<AggregatorSigner>>,
+        spl_program: &Program<Arc<AggregatorSigner>>,
+        pool_state: &PoolState,
+    ) -> Result<Self, AggregatorError> {
+        let amm_config = get_amm_config(raydium_program, pool_state.amm_config)
+            .await
+            .map_err(|e| AggregatorError::from_client("Failed to get amm config", e))?;
+
+        let vault_amounts = pool_state
+            .get_vault_amounts(spl_program)
+            .await
+            .map_err(|e| AggregatorError::from_client("Failed to get pool amounts", e))?;
+        let (reserve_0, reserve_1) = pool_state.reserves(vault_amounts)?;
+
+        let (transfer_fee_0, transfer_fee_1) = if pool_state.token_0_program == token_2022::ID
+            || pool_state.token_1_program == token_2022::ID
+        {
+            let epoch = client::rpc()
+                .get_epoch_info()
+                .await
+                .map_err(|e| AggregatorError::Rpc {
+                    context: "Failed to get epoch",
+                    message: e.to_string(),
+                })?
+                .epoch;
+            (
+                TransferFee::load(&pool_state.token_0_mint, &pool_state.token_0_program, epoch)
+                    .await?,
+                TransferFee::load(&pool_state.token_1_mint, &pool_state.token_1_program, epoch)
+                    .await?,
+            )
+        } else {
+            (TransferFee::NONE, TransferFee::NONE)
+        };
+
+        Ok(Self {
+            reserve_0,
+            reserve_1,
+            trade_fee_rate: amm_config.trade_fee_rate,
+            transfer_fee_0,
+            transfer_fee_1,
+        })
+    }
+
+    /// (reserve_in, reserve_out) for the swap direction
+    /// * `base_token` - true: token0 (WSOL) in, false: token1 in
+    pub fn reserves(&self, base_token: bool) -> (u64, u64) {
+        if base_token {
+            (self.reserve_0, self.reserve_1)
+        } else {
+            (self.reserve_1, self.reserve_0)
+        }
+    }
+
+    /// (transfer_fee_in, transfer_fee_out) for the swap direction
+    pub fn transfer_fees(&self, base_token: bool) -> (TransferFee, TransferFee) {
+        if base_token {
+            (self.transfer_fee_0, self.transfer_fee_1)
+        } else {
+            (self.transfer_fee_1, self.transfer_fee_0)
+        }
+    }
+
+    /// Swap exactly `amount_in` out of the vault. The curve only sees what is left after the
+    /// input's transfer fee, and the output's transfer fee comes off the payout.
+    pub fn exact_in(&self, amount_in: u64, base_token: bool) -> Result<SwapQuote, AggregatorError> {
+        let (reserve_in, reserve_out) = self.reserves(base_token);
+        let (fee_in, fee_out) = self.transfer_fees(base_token);
+
+        let transfer_fee_in = fee_in.fee(amount_in)?;
+        let swap = quote_exact_in(
+            amount_in - transfer_fee_in,
+            reserve_in,
+            reserve_out,
+            self.trade_fee_rate,
+        )?;
+        let transfer_fee_out = fee_out.fee(swap.amount_out)?;
+
+        Ok(SwapQuote {
+            amount_in,
+            amount_out: swap.amount_out - transfer_fee_out,
+            trade_fee: swap.trade_fee,
+            transfer_fee_in,
+            transfer_fee_out,
+        })
+    }
+
+    /// Input the vault has to send for exactly `amount_out` to arrive, transfer fees on both
+    /// sides grossed up
+    pub fn exact_out(
+        &self,
+        amount_out: u64,
+        base_token: bool,
+    ) -> Result<SwapQuote, AggregatorError> {
+        let (reserve_in, reserve_out) = self.reserves(base_token);
+        let (fee_in, fee_out) = self.transfer_fees(base_token);
+
+        let transfer_fee_out = fee_out.inverse_fee(amount_out)?;
+        let pool_amount_out =
+            amount_out
+                .checked_add(transfer_fee_out)
+                .ok_or(AggregatorError::MathOverflow(
+                    "swap amount out with transfer fee",
+                ))?;
+        let swap = quote_exact_out(
+            pool_amount_out,
+            reserve_in,
+            reserve_out,
+            self.trade_fee_rate,
+        )?;
+        let transfer_fee_in = fee_in.inverse_fee(swap.amount_in)?;
+
+        Ok(SwapQuote {
+            amount_in: swap.amount_in.checked_add(transfer_fee_in).ok_or(
+                AggregatorError::MathOverflow("swap amount in with transfer fee"),
+            )?,
+            amount_out,
+            trade_fee: swap.trade_fee,
+            transfer_fee_in,
+            transfer_fee_out,
+        })
+    }
+}
+
+/// Trade fee charged on `amount`, rounded up like the CPMM program does
+pub fn trading_fee(amount: u64, trade_fee_rate: u64) -> Result<u64, AggregatorError> {
+    ceil_div(
+        (amount as u128)
+            .checked_mul(trade_fee_rate as u128)
+            .ok_or(AggregatorError::MathOverflow("trading fee"))?,
+        FEE_RATE_DENOMINATOR as u128,
+    )
+    .and_then(|fee| u64::try_from(fee).ok())
+    .ok_or(AggregatorError::MathOverflow("trading fee"))
+}
+
+/// Output of swapping exactly `amount_in` on the x*y=k curve, after the trade fee
+/// is taken from the input (Raydium `swap_base_input`)
+pub fn quote_exact_in(
+    amount_in: u64,
+    reserve_in: u64,
+    reserve_out: u64,
+    trade_fee_rate: u64,
+) -> Result<SwapQuote, AggregatorError> {
+    let trade_fee = trading_fee(amount_in, trade_fee_rate)?;
+    let amount_in_less_fees = amount_in
+        .checked_sub(trade_fee)
+        .ok_or(AggregatorError::MathOverflow("amount in less fees"))?;
+
+    // amount_out = amount_in_less_fees * reserve_out / (reserve_in + amount_in_less_fees)
+    let amount_out = (amount_in_less_fees as u128)
+        .checked_mul(reserve_out as u128)
+        .and_then(|numerator| {
+            numerator.checked_div((reserve_in as u128).checked_add(amount_in_less_fees as u128)?)
+        })
+        .and_then(|result| u64::try_from(result).ok())
+        .ok_or(AggregatorError::MathOverflow("swap amount out"))?;
+
+    Ok(SwapQuote {
+        amount_in,
+        amount_out,
+        trade_fee,
+        transfer_fee_in: 0,
+        transfer_fee_out: 0,
+    })
+}
+
+/// Input needed to receive exactly `amount_out`, the trade fee grossed up on the input side
+/// (Raydium `swap_base_output`)
+pub fn quote_exact_out(
+    amount_out: u64,
+    reserve_in: u64,
+    reserve_out: u64,
+    trade_fee_rate: u64,
+) -> Result<SwapQuote, AggregatorError> {
+    if amount_out >= reserve_out {
+        return Err(AggregatorError::InsufficientLiquidity(format!(
+            "cannot buy {} out of a reserve of {}",
+            amount_out, reserve_out
+        )));
+    }
+
+    // amount_in_less_fees = ceil(reserve_in * amount_out / (reserve_out - amount_out))
+    let amount_in_less_fees = (reserve_in as u128)
+        .checked_mul(amount_out as u128)
+        .and_then(|numerator| ceil_div(numerator, (reserve_out - amount_out) as u128))
+        .ok_or(AggregatorError::MathOverflow("swap amount in"))?;
+
+    // Gross up so that trading_fee(amount_in) leaves at least amount_in_less_fees
+    let amount_in = if trade_fee_rate == 0 {
+        Some(amount_in_less_fees)
+    } else {
+        amount_in_less_fees
+            .checked_mul(FEE_RATE_DENOMINATOR as u128)
+            .and_then(|numerator| {
+                ceil_div(
+                    numerator,
+                    (FEE_RATE_DENOMINATOR as u128).checked_sub(trade_fee_rate as u128)?,
+                )
+            })
+    }
+    .and_then(|result| u64::try_from(result).ok())
+    .ok_or(AggregatorError::MathOverflow("swap amount in with fees"))?;
+
+    Ok(SwapQuote {
+        amount_in,
+        amount_out,
+        trade_fee: trading_fee(amount_in, trade_fee_rate)?,
+        transfer_fee_in: 0,
+        transfer_fee_out: 0,
+    })
+}
+
+fn ceil_div(numerator: u128, denominator: u128) -> Option<u128> {
+    if denominator == 0 {
+        return None;
+    }
+    numerator
+        .checked_add(denominator - 1)
+        .map(|sum| sum / denominator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 25 bps, the trade fee of the CPMM AmmConfig at index 0
+    const TRADE_FEE_RATE: u64 = 2500;
+
+    #[test]
+    fn exact_in_takes_fee_from_input() {
+        let quote =
+            quote_exact_in(1_000_000, 1_000_000_000, 2_000_000_000, TRADE_FEE_RATE).unwrap();
+        assert_eq!(
+            quote,
+            SwapQuote {
+                amount_in: 1_000_000,
+                amount_out: 1_993_011,
+                trade_fee: 2_500,
+                transfer_fee_in: 0,
+                transfer_fee_out: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn exact_in_includes_price_impact() {
+        // 20% of the input reserve, the spot price would give 200_000_000_000_000
+        let quote = quote_exact_in(
+            10_000_000_000,
+            50_000_000_000,
+            1_000_000_000_000_000,
+            TRADE_FEE_RATE,
+        )
+        .unwrap();
+        assert_eq!(quote.amount_out, 166_319_299_708_211);
+        assert_eq!(quote.trade_fee, 25_000_000);
+    }
+
+    #[test]
+    fn exact_in_rounds_fee_up() {
+        let quote = quote_exact_in(1, 1_000_000_000, 2_000_000_000, TRADE_FEE_RATE).unwrap();
+        assert_eq!(quote.trade_fee, 1);
+        assert_eq!(quote.amount_out, 0);
+    }
+
+    #[test]
+    fn exact_in_without_fee_is_plain_curve() {
+        let quote = quote_exact_in(1_000_000, 1_000_000_000, 2_000_000_000, 0).unwrap();
+        assert_eq!(quote.amount_out, 1_998_001);
+        assert_eq!(quote.trade_fee, 0);
+    }
+
+    #[test]
+    fn exact_in_matches_raydium_swap_base_input() {
+        // Worked through raydium-cp-swap's CurveCalculator::swap_base_input:
+        // fee = ceil(12_345_679 * 2500 / 1e6) = 30_865,
+        // out = floor(12_314_814 * 123_456_789 / (987_654_321 + 12_314_814)) = 1_520_394
+        let quote = quote_exact_in(12_345_679, 987_654_321, 123_456_789, TRADE_FEE_RATE).unwrap();
+        assert_eq!(quote.trade_fee, 30_865);
+        assert_eq!(quote.amount_out, 1_520_394);
+    }
+
+    #[test]
+    fn exact_out_matches_raydium_swap_base_output() {
+        // Worked through raydium-cp-swap's CurveCalculator::swap_base_output:
+        // swapped = ceil(7_654_321 * 123_456_789 / (987_654_321 - 7_654_321)) = 964_264,
+        // in = ceil(964_264 * 1e6 / (1e6 - 2500)) = 966_681, fee = ceil(966_681 * 2500 / 1e6) = 2_417
+        let quote = quote_exact_out(7_654_321, 123_456_789, 987_654_321, TRADE_FEE_RATE).unwrap();
+        assert_eq!(quote.amount_in, 966_681);
+        assert_eq!(quote.trade_fee, 2_417);
+    }
+
+    #[test]
+    fn exact_out_grosses_up_for_fee() {
+        let quote =
+            quote_exact_out(1_990_000, 1_000_000_000, 2_000_000_000, TRADE_FEE_RATE).unwrap();
+        assert_eq!(
+            quote,
+            SwapQuote {
+                amount_in: 998_489,
+                amount_out: 1_990_000,
+                trade_fee: 2_497,
+                transfer_fee_in: 0,
+                transfer_fee_out: 0,
+            }
+        );
+
+        let large = quote_exact_out(
+            100_000_000_000_000,
+            50_000_000_000,
+            1_000_000_000_000_000,
+            TRADE_FEE_RATE,
+        )
+        .unwrap();
+        assert_eq!(large.amount_in, 5_569_479_255);
+        assert_eq!(large.trade_fee, 13_923_699);
+    }
+
+    #[test]
+    fn exact_out_round_trips_through_exact_in() {
+        for amount_out in [1, 1_000, 1_990_000, 500_000_000] {
+            let quote =
+                quote_exact_out(amount_out, 1_000_000_000, 2_000_000_000, TRADE_FEE_RATE).unwrap();
+            let back = quote_exact_in(
+                quote.amount_in,
+                1_000_000_000,
+                2_000_000_000,
+                TRADE_FEE_RATE,
+            )
+            .unwrap();
+            assert!(back.amount_out >= amount_out);
+        }
+    }
+
+    #[test]
+    fn exact_out_rejects_draining_the_reserve() {
+        assert!(matches!(
+            quote_exact_out(2_000_000_000, 1_000_000_000, 2_000_000_000, TRADE_FEE_RATE),
+            Err(AggregatorError::InsufficientLiquidity(_))
+        ));
+    }
+
+    #[test]
+    fn quoter_picks_reserves_by_direction() {
+        let quoter = PoolQuoter {
+            reserve_0: 1_000_000_000,
+            reserve_1: 2_000_000_000,
+            trade_fee_rate: TRADE_FEE_RATE,
+            transfer_fee_0: TransferFee::NONE,
+            transfer_fee_1: TransferFee::NONE,
+        };
+        assert_eq!(
+            quoter.exact_in(1_000_000, true).unwrap().amount_out,
+            1_993_011
+        );
+        assert_eq!(
+            quoter.exact_in(2_000_000, false).unwrap(),
+            quote_exact_in(2_000_000, 2_000_000_000, 1_000_000_000, TRADE_FEE_RATE).unwrap()
+        );
+    }
+
+    #[test]
+    fn transfer_fees_come_off_both_sides_of_a_swap() {
+        // 1% token1 transfer fee, capped at 10_000
+        let quoter = PoolQuoter {
+            reserve_0: 1_000_000_000,
+            reserve_1: 2_000_000_000,
+            trade_fee_rate: TRADE_FEE_RATE,
+            transfer_fee_0: TransferFee::NONE,
+            transfer_fee_1: TransferFee {
+                basis_points: 100,
+                maximum_fee: 10_000,
+            },
+        };
+
+        let buy = quoter.exact_in(1_000_000, true).unwrap();
+        assert_eq!(buy.transfer_fee_in, 0);
+        assert_eq!(buy.transfer_fee_out, 10_000);
+        assert_eq!(buy.amount_out, 1_993_011 - 10_000);
+
+        let sell = quoter.exact_in(500_000, false).unwrap();
+        assert_eq!(sell.transfer_fee_in, 5_000);
+        assert_eq!(
+            sell.amount_out,
+            quote_exact_in(495_000, 2_000_000_000, 1_000_000_000, TRADE_FEE_RATE)
+                .unwrap()
+                .amount_out
+        );
+
+        // Buying exact amounts grosses up for the output's fee
+        let exact = quoter.exact_out(100_000, true).unwrap();
+        assert_eq!(exact.transfer_fee_out, 1_011);
+        assert!(quoter.exact_in(exact.amount_in, true).unwrap().amount_out >= 100_000);
+    }
+
+    #[test]
+    fn transfer_fee_rounds_like_token_2022() {
+        let fee = TransferFee {
+            basis_points: 250,
+            maximum_fee: 1_000,
+        };
+        assert_eq!(fee.fee(100).unwrap(), 3);
+        assert_eq!(fee.fee(1_000_000).unwrap(), 1_000);
+        assert_eq!(fee.post_fee_amount(100).unwrap(), 97);
+        // 97 arriving needs 100 sent, 3 of it withheld
+        assert_eq!(fee.inverse_fee(97).unwrap(), 3);
+        assert_eq!(TransferFee::NONE.inverse_fee(97).unwrap(), 0);
+    }
+}
@@ -1,270 +1,376 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
-use anchor_client::{solana_sdk::signature::Keypair, Program};
+use anchor_client::Program;
 use anchor_lang::prelude::Pubkey;
+use anchor_spl::token::spl_token;
+use tracing::{field, info, instrument, warn, Span};
 
 use crate::{
+    config,
+    error::AggregatorError,
+    journal::Action,
+    pool::RegisteredPool,
     raydium::get_pool_state,
-    utils::{get_token_account_balance, POOL_ADDRESS, VAULT_PDA},
+    signer::AggregatorSigner,
+    utils::{cluster_now, get_token_account_balance, VAULT_PDA},
 };
 
-use super::utils::calculate_lp_amount;
-
-pub async fn process_lp_swap(
-    program: &Program<Rc<Keypair>>,
-    raydium_program: &Program<Rc<Keypair>>,
-    spl_program: &Program<Rc<Keypair>>,
-    aggregator_keypair: &Keypair,
-    swap_amount: u64,
-    base_token: bool, // true: swap WSOL into other token, false: swap other token into WSOL
-    slippage: u64,    // slippage tolerance (e.g., 99 for 99%)
-) -> Result<(String, u64), String> {
-    // TODO: take in pool_state as param, maybe not
-    // Get pool state and amounts
-    let pool_state = get_pool_state(raydium_program, POOL_ADDRESS)
-        .await
-        .map_err(|e| format!("Failed to get pool state: {}", e))?;
-
-    let pool_amounts = pool_state
-        .get_vault_amounts(spl_program)
-        .await
-        .map_err(|e| format!("Failed to get pool amounts: {}", e))?;
-
-    println!(
-        "Pool amounts - WSOL: {}, Other token: {}",
-        pool_amounts.0, pool_amounts.1
-    );
+use super::{
+    instructions::{
+        lp_deposit, lp_swap, lp_withdraw, TxComposer, LP_DEPOSIT_COMPUTE_UNITS,
+        LP_SWAP_COMPUTE_UNITS, LP_WITHDRAW_COMPUTE_UNITS,
+    },
+    oracle::check_price,
+    quote::PoolQuoter,
+    zap::{size_deposit, size_unwind_swap, zap_in, zap_out},
+};
 
-    // Calculate expected output based on the current pool ratio and direction
-    let amount_out = if base_token {
-        // Swap WSOL to other token: amount_out = swap_amount * (pool_amounts.1 / pool_amounts.0)
-        (swap_amount as u128)
-            .checked_mul(pool_amounts.1 as u128)
-            .and_then(|product| product.checked_div(pool_amounts.0 as u128))
-            .ok_or("Failed to calculate amount out: overflow or division by zero")?
-    } else {
-        // Swap other token to WSOL: amount_out = swap_amount * (pool_amounts.0 / pool_amounts.1)
-        (swap_amount as u128)
-            .checked_mul(pool_amounts.0 as u128)
-            .and_then(|product| product.checked_div(pool_amounts.1 as u128))
-            .ok_or("Failed to calculate amount out: overflow or division by zero")?
-    };
-
-    let minimum_amount_out = amount_out
-        .checked_mul(slippage as u128)
-        .and_then(|with_slippage| with_slippage.checked_div(100))
-        .and_then(|final_result| u64::try_from(final_result).ok())
-        .ok_or("Failed to apply slippage: overflow or conversion error")?;
-
-    println!(
-        "Swapping {} token0 for {} token1 (expected: {})",
-        swap_amount, minimum_amount_out, amount_out
-    );
+/// Slippage tolerance for swaps and LP withdraws, in percent of the quoted amount
+const SLIPPAGE: u64 = 95;
 
-    if minimum_amount_out == 0 {
-        return Err("Minimum output amount cannot be zero".to_string());
-    }
-
-    // Execute the swap
-    let swap_tx = super::instructions::lp_swap(
-        program,
-        raydium_program,
-        aggregator_keypair,
-        swap_amount,
-        minimum_amount_out,
-        base_token, // Pass base_token to determine swap direction
-    )
-    .await?;
+fn with_slippage(amount: u64, what: &'static str) -> Result<u64, AggregatorError> {
+    (amount as u128)
+        .checked_mul(SLIPPAGE as u128)
+        .and_then(|product| product.checked_div(100))
+        .and_then(|result| u64::try_from(result).ok())
+        .ok_or(AggregatorError::MathOverflow(what))
+}
 
-    // Return the transaction signature and the expected output amount
-    Ok((swap_tx, minimum_amount_out))
+/// Outcome of a single-sided WSOL deposit into LP
+pub struct LpDepositResult {
+    /// Swap and deposit land together in this transaction
+    pub tx: String,
+    pub lp_token_amount: u64,
+    /// WSOL and token1 expected to stay in the vault's ATAs after the deposit
+    pub dust_token_0: u64,
+    pub dust_token_1: u64,
 }
 
+#[instrument(
+    name = "lp_deposit",
+    skip_all,
+    fields(
+        pool = %pool_address,
+        deposit_amount,
+        lp_token_amount = field::Empty,
+        signature = field::Empty,
+        program_logs = field::Empty,
+    )
+)]
 pub async fn process_lp_deposit(
-    program: &Program<Rc<Keypair>>,
-    raydium_program: &Program<Rc<Keypair>>,
-    spl_program: &Program<Rc<Keypair>>,
-    aggregator_keypair: &Keypair,
+    program: &Program<Arc<AggregatorSigner>>,
+    raydium_program: &Program<Arc<AggregatorSigner>>,
+    spl_program: &Program<Arc<AggregatorSigner>>,
+    aggregator_signer: &AggregatorSigner,
     pool_address: Pubkey,
     deposit_amount: u64, // Amount of WSOL you want to deposit, will split and swap into lp
-) -> Result<(String, String, u64), String> {
-    // Return (swap_tx, deposit_tx, lp_token_amount)
-    // Swap half
-    let wsol_to_swap = deposit_amount
-        .checked_div(2)
-        .ok_or("Failed to calculate WSOL swap amount: division error")?;
-
-    let wsol_leftover = deposit_amount
-        .checked_sub(wsol_to_swap)
-        .ok_or("Failed to calculate WSOL leftover amount: subtraction error")?;
-
-    let slippage = 95;
-    let (swap_tx, actual_amount_in) = process_lp_swap(
-        program,
-        raydium_program,
-        spl_program,
-        aggregator_keypair,
-        wsol_to_swap,
-        true,
-        slippage,
-    )
-    .await
-    .map_err(|e| format!("Failed to process LP swap: {}", e))?;
-
-    println!(
-        "Swapped {} WSOL for {} tokens",
-        wsol_to_swap, actual_amount_in
-    );
-    println!("Tx: {}", swap_tx);
-
-    // NOTE: Pull in new pool_state after swap
+) -> Result<LpDepositResult, AggregatorError> {
     let pool_state = get_pool_state(raydium_program, pool_address)
         .await
-        .map_err(|e| format!("Failed to get pool state: {}", e))?;
+        .map_err(|e| AggregatorError::from_client("Failed to get pool state", e))?;
+    pool_state
+        .check_swap(cluster_now().await?)
+        .and_then(|()| pool_state.check_deposit())
+        .map_err(|gate| AggregatorError::PoolNotApproved {
+            pool: pool_address,
+            gate,
+        })?;
+
+    // Swap just enough WSOL that the remainder matches the post-swap reserves ratio
+    let quoter = PoolQuoter::load(raydium_program, spl_program, &pool_state).await?;
+    // Don't buy into a pushed price, a sandwich would sell it back to us
+    check_price(raydium_program, pool_address, &pool_state, &quoter).await?;
+    let zap = zap_in(deposit_amount, &quoter)?;
+    let wsol_to_swap = zap.swap_amount;
+    let wsol_leftover = zap.remaining_amount;
+
+    info!(
+        reserve_0 = quoter.reserve_0,
+        reserve_1 = quoter.reserve_1,
+        trade_fee_rate = quoter.trade_fee_rate,
+        "Pool reserves"
+    );
 
-    let lp_supply = pool_state.lp_supply;
-    let (pool_amount0, pool_amount1) = pool_state
-        .get_vault_amounts(spl_program)
-        .await
-        .map_err(|e| format!("Failed to get vault amounts: {}", e))?;
+    let minimum_amount_out = with_slippage(zap.swap_amount_out, "swap slippage")?;
+    if minimum_amount_out == 0 {
+        return Err(AggregatorError::InsufficientLiquidity(format!(
+            "swap of {} yields zero minimum output",
+            wsol_to_swap
+        )));
+    }
 
-    let lp_token_amount = calculate_lp_amount(
-        wsol_leftover,
-        actual_amount_in,
-        lp_supply,
-        pool_amount0,
-        pool_amount1,
+    // token1 left over from earlier deposits goes in next to the swap output
+    let token1_dust = get_token_account_balance(
+        spl_program,
+        &VAULT_PDA,
+        &pool_state.token_1_mint,
+        &pool_state.token_1_program,
+    )
+    .await?;
+    let deposit = size_deposit(
+        &zap,
+        minimum_amount_out,
+        token1_dust,
+        pool_state.lp_supply,
+        &quoter,
     )?;
+    let lp_token_amount = deposit.lp_token_amount;
+
+    info!(
+        swap_amount = wsol_to_swap,
+        swap_amount_out = zap.swap_amount_out,
+        minimum_swap_out = minimum_amount_out,
+        wsol_leftover,
+        "Zapping WSOL into LP"
+    );
 
     // Make sure lp_token_amount != 0
     if lp_token_amount == 0 {
-        return Err("LP token amount cannot be zero".to_string());
+        return Err(AggregatorError::InsufficientLiquidity(format!(
+            "deposit of {} WSOL yields zero LP tokens",
+            deposit_amount
+        )));
     }
+    Span::current().record("lp_token_amount", lp_token_amount);
+
+    // The deposit is sized to what the swap guarantees, a swap that can't fill that reverts
+    // both together
+    let tx = TxComposer::new(program, aggregator_signer)
+        .push(
+            lp_swap(
+                aggregator_signer,
+                pool_address,
+                &pool_state,
+                wsol_to_swap,
+                minimum_amount_out,
+                true,
+            ),
+            LP_SWAP_COMPUTE_UNITS,
+        )
+        .push(
+            lp_deposit(
+                aggregator_signer,
+                pool_address,
+                &pool_state,
+                lp_token_amount,
+                deposit.maximum_token_0,
+                deposit.maximum_token_1,
+            ),
+            LP_DEPOSIT_COMPUTE_UNITS,
+        )
+        .journal(Action::LpDeposit {
+            pool: pool_address,
+            deposit_amount,
+            swap_amount: wsol_to_swap,
+            minimum_swap_out: minimum_amount_out,
+            lp_token_amount,
+            maximum_token_0: deposit.maximum_token_0,
+            maximum_token_1: deposit.maximum_token_1,
+        })
+        .send("Failed to send swap and deposit transaction")
+        .await?;
+
+    // Expected at the quoted fill, a worse one leaves less token1 behind
+    let dust_token_0 = wsol_leftover.saturating_sub(deposit.token_0_used);
+    let dust_token_1 = token1_dust
+        .saturating_add(zap.swap_amount_out)
+        .saturating_sub(deposit.token_1_used);
+
+    info!(
+        token_0_used = deposit.token_0_used,
+        token_1_used = deposit.token_1_used,
+        dust_token_0,
+        dust_token_1,
+        "LP deposit landed"
+    );
 
-    let deposit_tx = super::instructions::lp_deposit(
-        program,
-        raydium_program,
-        aggregator_keypair,
+    Ok(LpDepositResult {
+        tx,
         lp_token_amount,
-        wsol_leftover,
-        actual_amount_in,
-    )
-    .await?;
-
-    println!(
-        "Deposited {} WSOL and {} tokens for {} LP tokens",
-        wsol_leftover, actual_amount_in, lp_token_amount
-    );
-    println!("Tx: {}", deposit_tx);
+        dust_token_0,
+        dust_token_1,
+    })
+}
 
-    Ok((swap_tx, deposit_tx, lp_token_amount))
+/// Outcome of unwinding LP back into WSOL
+pub struct LpWithdrawResult {
+    /// Withdraw and swap back land together in this transaction
+    pub tx: String,
+    pub lp_burned: u64,
+    /// WSOL expected from the burn plus the token1 swap, before slippage
+    pub expected_wsol: u64,
 }
 
+/// Burn just enough of the vault's LP in `pool_address` to raise `target_wsol` WSOL once the
+/// token1 side is swapped back, plus the configured safety margin. Pass `u64::MAX` to unwind
+/// the whole position.
+#[instrument(
+    name = "lp_withdraw",
+    skip_all,
+    fields(
+        pool = %pool_address,
+        target_wsol,
+        lp_token_amount = field::Empty,
+        signature = field::Empty,
+        program_logs = field::Empty,
+    )
+)]
 pub async fn process_lp_withdraw(
-    program: &Program<Rc<Keypair>>,
-    raydium_program: &Program<Rc<Keypair>>,
-    spl_program: &Program<Rc<Keypair>>,
-    aggregator_keypair: &Keypair,
+    program: &Program<Arc<AggregatorSigner>>,
+    raydium_program: &Program<Arc<AggregatorSigner>>,
+    spl_program: &Program<Arc<AggregatorSigner>>,
+    aggregator_signer: &AggregatorSigner,
     pool_address: Pubkey,
-) -> Result<String, String> {
+    target_wsol: u64,
+) -> Result<LpWithdrawResult, AggregatorError> {
     // Get pool state and amounts
     let pool_state = get_pool_state(raydium_program, pool_address)
         .await
-        .map_err(|e| format!("Failed to get pool state: {}", e))?;
-
-    let (pool_amount0, pool_amount1) = pool_state
-        .get_vault_amounts(spl_program)
-        .await
-        .map_err(|e| format!("Failed to get vault amounts: {}", e))?;
+        .map_err(|e| AggregatorError::from_client("Failed to get pool state", e))?;
+    // The token1 side is swapped back in the same transaction, so both have to be allowed
+    let now = cluster_now().await?;
+    pool_state
+        .check_withdraw()
+        .and_then(|()| pool_state.check_swap(now))
+        .map_err(|gate| AggregatorError::PoolNotApproved {
+            pool: pool_address,
+            gate,
+        })?;
+
+    let quoter = PoolQuoter::load(raydium_program, spl_program, &pool_state).await?;
+    // The token1 side is sold back, so a depressed price costs the vault WSOL
+    check_price(raydium_program, pool_address, &pool_state, &quoter).await?;
 
     let lp_supply = pool_state.lp_supply;
 
-    println!("lp mint: {}", pool_state.lp_mint);
-    println!(
-        "Current pool amounts - WSOL: {}, Token1: {}, lp supply: {}",
-        pool_amount0, pool_amount1, lp_supply
-    );
-
     // Get the LP token balance of VAULT_PDA
-    let lp_balance = get_token_account_balance(spl_program, &*VAULT_PDA, &pool_state.lp_mint)
-        .await
-        .map_err(|e| format!("Failed to get LP token balance: {}", e))?;
-
-    println!("LP token balance owned by vault: {}", lp_balance);
+    let lp_balance =
+        get_token_account_balance(spl_program, &VAULT_PDA, &pool_state.lp_mint, &spl_token::ID)
+            .await?;
+
+    info!(
+        lp_mint = %pool_state.lp_mint,
+        reserve_0 = quoter.reserve_0,
+        reserve_1 = quoter.reserve_1,
+        lp_supply,
+        lp_balance,
+        "Pool reserves"
+    );
 
     // Check if we have any LP tokens to burn
     if lp_balance == 0 {
-        return Err("No LP tokens available to withdraw".to_string());
+        return Err(AggregatorError::InsufficientLiquidity(
+            "no LP tokens available to withdraw".to_string(),
+        ));
     }
 
-    // Use all available LP tokens for withdrawal
-    let lp_to_burn = lp_balance;
-
-    // Calculate expected token amounts based on LP amount
-    let wsol_received = (lp_to_burn as u128)
-        .checked_mul(pool_amount0 as u128)
-        .and_then(|product| product.checked_div(lp_supply as u128))
-        .and_then(|result| u64::try_from(result).ok())
-        .ok_or("Failed to calculate WSOL received: overflow or conversion error")?;
-
-    let token1_received = (lp_to_burn as u128)
-        .checked_mul(pool_amount1 as u128)
-        .and_then(|product| product.checked_div(lp_supply as u128))
-        .and_then(|result| u64::try_from(result).ok())
-        .ok_or("Failed to calculate Token1 received: overflow or conversion error")?;
-
-    let slippage = 95; // 95% slippage tolerance
-    let minimum_wsol_received = (wsol_received as u128)
-        .checked_mul(slippage as u128)
-        .and_then(|product| product.checked_div(100))
-        .and_then(|result| u64::try_from(result).ok())
-        .ok_or("Failed to calculate minimum WSOL received")?;
-
-    let minimum_token1_received = (token1_received as u128)
-        .checked_mul(slippage as u128)
-        .and_then(|product| product.checked_div(100))
-        .and_then(|result| u64::try_from(result).ok())
-        .ok_or("Failed to calculate minimum Token1 received")?;
+    // Gross up the target so quote drift between now and landing doesn't leave us short
+    let margin_bps = config::get().aggregator.withdraw_safety_margin_bps;
+    let target_with_margin = (target_wsol as u128)
+        .checked_mul(10_000 + margin_bps as u128)
+        .map(|product| product / 10_000)
+        .map_or(u64::MAX, |result| u64::try_from(result).unwrap_or(u64::MAX));
+
+    let zap = zap_out(target_with_margin, lp_balance, lp_supply, &quoter)?;
+    let lp_to_burn = zap.lp_amount;
+
+    if lp_to_burn == 0 {
+        return Err(AggregatorError::InsufficientLiquidity(format!(
+            "withdraw of {} WSOL burns zero LP tokens",
+            target_wsol
+        )));
+    }
+    if zap.wsol_amount() < target_with_margin {
+        warn!(
+            covered_wsol = zap.wsol_amount(),
+            target_with_margin, "LP position doesn't cover the target, burning all LP tokens"
+        );
+    }
 
-    println!(
-        "Withdrawing {} LP tokens, expecting at least {} WSOL and {} Token1",
-        lp_to_burn, minimum_wsol_received, minimum_token1_received
-    );
+    let minimum_wsol_received = with_slippage(zap.token_0_amount, "minimum WSOL received")?;
+    let minimum_token1_received = with_slippage(zap.token_1_amount, "minimum Token1 received")?;
 
-    let withdraw_tx = super::instructions::lp_withdraw(
-        program,
-        raydium_program,
-        aggregator_keypair,
-        lp_to_burn,
-        minimum_wsol_received,
-        minimum_token1_received,
-    )
-    .await
-    .map_err(|e| format!("Failed to execute LP withdrawal: {}", e))?;
-
-    // Get total token1 balance after withdrawal
-    let token1_balance =
-        get_token_account_balance(spl_program, &*VAULT_PDA, &pool_state.token_1_mint).await?;
-
-    // Swap ALL token1 owned by VAULT_PDA into WSOL
-    let slippage = 95; // 95% slippage tolerance
-    let (swap_tx, wsol_from_swap) = process_lp_swap(
-        program,
-        raydium_program,
+    // Swap back the token1 the burn guarantees along with any token1 dust already in the
+    // vault, so a payout at the minimum still covers the swap
+    let token1_dust = get_token_account_balance(
         spl_program,
-        aggregator_keypair,
-        token1_balance, // Swap ALL token1 balance
-        false,          // Swap Token1 to WSOL
-        slippage,
+        &VAULT_PDA,
+        &pool_state.token_1_mint,
+        &pool_state.token_1_program,
     )
-    .await
-    .map_err(|e| format!("Failed to swap Token1 to WSOL: {}", e))?;
-
-    println!(
-        "Swapped {} Token1 for at least {} WSOL. Swap tx: {}",
-        token1_balance, wsol_from_swap, swap_tx
+    .await?;
+    let swap = size_unwind_swap(&zap, minimum_token1_received, token1_dust, &quoter)?;
+    let token1_to_swap = swap.amount_in;
+    let minimum_wsol_from_swap = with_slippage(swap.amount_out, "swap slippage")?;
+
+    Span::current().record("lp_token_amount", lp_to_burn);
+    info!(
+        expected_wsol = zap.wsol_amount(),
+        minimum_token_0 = minimum_wsol_received,
+        minimum_token_1 = minimum_token1_received,
+        swap_amount = token1_to_swap,
+        minimum_swap_out = minimum_wsol_from_swap,
+        "Unwinding LP into WSOL"
     );
 
-    // Return the withdrawal transaction signature
-    Ok(withdraw_tx)
+    // Burn and swap back in one transaction so the vault is never left holding token1
+    let tx = TxComposer::new(program, aggregator_signer)
+        .push(
+            lp_withdraw(
+                aggregator_signer,
+                pool_address,
+                &pool_state,
+                lp_to_burn,
+                minimum_wsol_received,
+                minimum_token1_received,
+            ),
+            LP_WITHDRAW_COMPUTE_UNITS,
+        )
+        .push(
+            lp_swap(
+                aggregator_signer,
+                pool_address,
+                &pool_state,
+                token1_to_swap,
+                minimum_wsol_from_swap,
+                false,
+            ),
+            LP_SWAP_COMPUTE_UNITS,
+        )
+        .journal(Action::LpWithdraw {
+            pool: pool_address,
+            target_wsol,
+            lp_token_amount: lp_to_burn,
+            minimum_token_0: minimum_wsol_received,
+            minimum_token_1: minimum_token1_received,
+            swap_amount: token1_to_swap,
+            minimum_swap_out: minimum_wsol_from_swap,
+        })
+        .send("Failed to send withdraw and swap transaction")
+        .await?;
+
+    info!("LP withdraw landed");
+
+    Ok(LpWithdrawResult {
+        tx,
+        lp_burned: lp_to_burn,
+        expected_wsol: zap.wsol_amount(),
+    })
+}
+
+/// The vault's LP balance in `pool` and the WSOL it would unwind to at current reserves,
+/// token1 side swapped back
+pub async fn vault_lp_position(
+    raydium_program: &Program<Arc<AggregatorSigner>>,
+    spl_program: &Program<Arc<AggregatorSigner>>,
+    pool: &RegisteredPool,
+) -> Result<(u64, u64), AggregatorError> {
+    let lp_balance = pool.vault_lp_balance(spl_program).await?;
+    if lp_balance == 0 {
+        return Ok((0, 0));
+    }
+
+    let quoter = PoolQuoter::load(raydium_program, spl_program, &pool.pool_state).await?;
+    let zap = zap_out(u64::MAX, lp_balance, pool.pool_state.lp_supply, &quoter)?;
+    Ok((lp_balance, zap.wsol_amount()))
 }
@@ -1,3 +1,5 @@
+use crate::error::AggregatorError;
+
 /// Calculate the expected LP token amount based on the token amounts and pool state
 /// 
 /// This follows Raydium's specified_tokens_to_lp_tokens logic:
@@ -16,19 +18,43 @@ pub fn calculate_lp_amount(
     lp_supply: u64,
     pool_amount0: u64,
     pool_amount1: u64,
-) -> Result<u64, String> {
+) -> Result<u64, AggregatorError> {
     let lp_amount = std::cmp::min(
         ((token0_amount as u128)
             .checked_mul(lp_supply as u128)
             .and_then(|product| product.checked_div(pool_amount0 as u128))
             .and_then(|result| u64::try_from(result).ok()))
-            .ok_or("Failed to calculate LP amount from token0")?,
+            .ok_or(AggregatorError::MathOverflow("LP amount from token0"))?,
         ((token1_amount as u128)
             .checked_mul(lp_supply as u128)
             .and_then(|product| product.checked_div(pool_amount1 as u128))
             .and_then(|result| u64::try_from(result).ok()))
-            .ok_or("Failed to calculate LP amount from token1")?
+            .ok_or(AggregatorError::MathOverflow("LP amount from token1"))?
     );
     
     Ok(lp_amount)
-}
\ No newline at end of file
+}
+
+/// Calculate the token amounts pulled from the depositor for `lp_amount` LP tokens
+///
+/// This follows Raydium's lp_tokens_to_trading_tokens with RoundDirection::Ceiling:
+/// token_amount = ceil(lp_amount * pool_amount / lp_supply)
+pub fn calculate_deposit_amounts(
+    lp_amount: u64,
+    lp_supply: u64,
+    pool_amount0: u64,
+    pool_amount1: u64,
+) -> Result<(u64, u64), AggregatorError> {
+    let ceil_share = |pool_amount: u64| {
+        (lp_amount as u128)
+            .checked_mul(pool_amount as u128)
+            .and_then(|product| product.checked_add((lp_supply as u128).checked_sub(1)?))
+            .and_then(|product| product.checked_div(lp_supply as u128))
+            .and_then(|result| u64::try_from(result).ok())
+    };
+
+    Ok((
+        ceil_share(pool_amount0).ok_or(AggregatorError::MathOverflow("deposit token0 amount"))?,
+        ceil_share(pool_amount1).ok_or(AggregatorError::MathOverflow("deposit token1 amount"))?,
+    ))
+}
@@ -0,0 +1,384 @@
+use crate::error::AggregatorError;
+
+use super::{
+    quote::{PoolQuoter, SwapQuote},
+    utils::{calculate_deposit_amounts, calculate_lp_amount},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZapQuote {
+    /// WSOL to swap into token1
+    pub swap_amount: u64,
+    /// token1 expected to arrive from the swap
+    pub swap_amount_out: u64,
+    /// WSOL left to deposit next to the swapped token1
+    pub remaining_amount: u64,
+    /// Pool (token0, token1) reserves once the swap lands at the quoted fill
+    pub reserves_after: (u64, u64),
+}
+
+/// Solve for the WSOL swap amount that leaves the remaining WSOL and the swapped token1
+/// in the same ratio as the pool's post-swap reserves, so the deposit uses nearly all of
+/// `deposit_amount`.
+///
+/// The swap output is quoted with the trade fee, price impact and transfer fees, and the
+/// balance condition `remaining / deposited_out >= reserve_0_after / reserve_1_after` is
+/// monotonic in the swap amount, so we binary search for the largest swap that still
+/// satisfies it. `deposited_out` is the token1 that reaches the pool once its transfer fee is
+/// withheld a second time on the deposit.
+pub fn zap_in(deposit_amount: u64, quoter: &PoolQuoter) -> Result<ZapQuote, AggregatorError> {
+    let (reserve_in, reserve_out) = quoter.reserves(true);
+    if reserve_in == 0 || reserve_out == 0 {
+        return Err(AggregatorError::InsufficientLiquidity(
+            "cannot zap into a pool with an empty reserve".to_string(),
+        ));
+    }
+
+    let quote = |swap_amount: u64| -> Result<(ZapQuote, bool), AggregatorError> {
+        let swap = quoter.exact_in(swap_amount, true)?;
+        let amount_out = swap.amount_out;
+        let remaining_amount = deposit_amount - swap_amount;
+
+        // The pool keeps what the transfer fees leave of the input and pays out the output
+        // before its fee is withheld
+        let reserve_in_after = reserve_in as u128 + (swap_amount - swap.transfer_fee_in) as u128;
+        let reserve_out_after = (reserve_out - amount_out - swap.transfer_fee_out) as u128;
+        let deposited_out = quoter.transfer_fee_1.post_fee_amount(amount_out)?;
+        let deposited_remaining = quoter.transfer_fee_0.post_fee_amount(remaining_amount)?;
+
+        // deposited_remaining * reserve_out_after >= deposited_out * reserve_in_after
+        let balanced = (deposited_remaining as u128)
+            .checked_mul(reserve_out_after)
+            .zip((deposited_out as u128).checked_mul(reserve_in_after))
+            .map(|(lhs, rhs)| lhs >= rhs)
+            .ok_or(AggregatorError::MathOverflow("zap balance"))?;
+
+        Ok((
+            ZapQuote {
+                swap_amount,
+                swap_amount_out: amount_out,
+                remaining_amount,
+                reserves_after: (
+                    u64::try_from(reserve_in_after)
+                        .map_err(|_| AggregatorError::MathOverflow("post-swap token0 reserve"))?,
+                    reserve_out_after as u64,
+                ),
+            },
+            balanced,
+        ))
+    };
+
+    // Swapping nothing is always balanced, swapping everything never is
+    let (mut low, mut high) = (0, deposit_amount);
+    while low < high {
+        let mid = low + (high - low).div_ceil(2);
+        if quote(mid)?.1 {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+
+    Ok(quote(low)?.0)
+}
+
+/// LP deposit sent in the same transaction as a `ZapQuote` swap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DepositQuote {
+    pub lp_token_amount: u64,
+    /// Deposit limits, what the vault holds for certain once the swap lands
+    pub maximum_token_0: u64,
+    pub maximum_token_1: u64,
+    /// What the deposit takes out of the vault, transfer fees included
+    pub token_0_used: u64,
+    pub token_1_used: u64,
+}
+
+/// Size the deposit following `zap`'s swap from what the swap guarantees,
+/// `minimum_amount_out`, not the quote. Any fill the slippage bound lets through then
+/// leaves the vault enough token1 for the deposit, and a better fill just leaves dust.
+pub fn size_deposit(
+    zap: &ZapQuote,
+    minimum_amount_out: u64,
+    token1_dust: u64,
+    lp_supply: u64,
+    quoter: &PoolQuoter,
+) -> Result<DepositQuote, AggregatorError> {
+    let maximum_token_0 = zap.remaining_amount;
+    let maximum_token_1 = token1_dust
+        .checked_add(minimum_amount_out)
+        .ok_or(AggregatorError::MathOverflow("token1 deposit amount"))?;
+
+    // Post-swap reserves bounded from above on both sides, so the LP tokens are priced at
+    // no less than the deposit will actually cost. The WSOL side counts the whole input,
+    // before the protocol and fund fee cut leaves the pool. The token1 side assumes a fill at
+    // the minimum, the least token1 the swap can take out.
+    let reserve_0 = zap.reserves_after.0;
+    let reserve_1 = quoter
+        .reserve_1
+        .checked_sub(minimum_amount_out)
+        .ok_or(AggregatorError::MathOverflow("post-swap token1 reserve"))?;
+
+    // Size the LP from what reaches the pool once transfer fees are withheld
+    let lp_token_amount = calculate_lp_amount(
+        quoter.transfer_fee_0.post_fee_amount(maximum_token_0)?,
+        quoter.transfer_fee_1.post_fee_amount(maximum_token_1)?,
+        lp_supply,
+        reserve_0,
+        reserve_1,
+    )?;
+
+    let (token_0_deposited, token_1_deposited) =
+        calculate_deposit_amounts(lp_token_amount, lp_supply, reserve_0, reserve_1)?;
+    // The pool pulls the transfer fee on top of what it credits
+    let token_0_used = token_0_deposited
+        .checked_add(quoter.transfer_fee_0.inverse_fee(token_0_deposited)?)
+        .ok_or(AggregatorError::MathOverflow(
+            "token0 deposit with transfer fee",
+        ))?;
+    let token_1_used = token_1_deposited
+        .checked_add(quoter.transfer_fee_1.inverse_fee(token_1_deposited)?)
+        .ok_or(AggregatorError::MathOverflow(
+            "token1 deposit with transfer fee",
+        ))?;
+
+    Ok(DepositQuote {
+        lp_token_amount,
+        maximum_token_0,
+        maximum_token_1,
+        token_0_used,
+        token_1_used,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZapOutQuote {
+    /// LP tokens to burn
+    pub lp_amount: u64,
+    /// WSOL and token1 arriving from the burn, after transfer fees
+    pub token_0_amount: u64,
+    pub token_1_amount: u64,
+    /// WSOL expected from swapping `token_1_amount` back
+    pub swap_amount_out: u64,
+    /// Pool (token0, token1) reserves once the burn lands
+    pub reserves_after: (u64, u64),
+}
+
+impl ZapOutQuote {
+    /// Total WSOL expected once the token1 side is swapped back
+    pub fn wsol_amount(&self) -> u64 {
+        self.token_0_amount.saturating_add(self.swap_amount_out)
+    }
+}
+
+/// Find the smallest LP burn, capped at `lp_balance`, whose WSOL payout plus the WSOL from
+/// swapping the token1 payout at the post-burn reserves covers `target_amount`.
+///
+/// Burns are priced like Raydium's lp_tokens_to_trading_tokens with RoundDirection::Floor.
+pub fn zap_out(
+    target_amount: u64,
+    lp_balance: u64,
+    lp_supply: u64,
+    quoter: &PoolQuoter,
+) -> Result<ZapOutQuote, AggregatorError> {
+    if lp_supply == 0 {
+        return Err(AggregatorError::InsufficientLiquidity(
+            "cannot zap out of a pool with no LP supply".to_string(),
+        ));
+    }
+
+    let quote = |lp_amount: u64| -> Result<ZapOutQuote, AggregatorError> {
+        let share = |pool_amount: u64| {
+            (lp_amount as u128)
+                .checked_mul(pool_amount as u128)
+                .map(|product| product / lp_supply as u128)
+                .and_then(|result| u64::try_from(result).ok())
+                .ok_or(AggregatorError::MathOverflow("LP burn amount"))
+        };
+        let pool_amount_0 = share(quoter.reserve_0)?;
+        let pool_amount_1 = share(quoter.reserve_1)?;
+        let token_0_amount = quoter.transfer_fee_0.post_fee_amount(pool_amount_0)?;
+        let token_1_amount = quoter.transfer_fee_1.post_fee_amount(pool_amount_1)?;
+
+        // token1 goes back in against the reserves left after the burn
+        let after_burn = PoolQuoter {
+            reserve_0: quoter.reserve_0 - pool_amount_0,
+            reserve_1: quoter.reserve_1 - pool_amount_1,
+            ..*quoter
+        };
+        let swap_amount_out = after_burn.exact_in(token_1_amount, false)?.amount_out;
+
+        Ok(ZapOutQuote {
+            lp_amount,
+            token_0_amount,
+            token_1_amount,
+            swap_amount_out,
+            reserves_after: (after_burn.reserve_0, after_burn.reserve_1),
+        })
+    };
+
+    let lp_balance = lp_balance.min(lp_supply);
+    let (mut low, mut high) = (0, lp_balance);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if quote(mid)?.wsol_amount() >= target_amount {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+
+    quote(low)
+}
+
+/// Size the swap following `zap`'s burn from what the burn guarantees,
+/// `minimum_token_1_received`, plus the token1 dust already in the vault, not the quote.
+/// Any payout the slippage bound lets through then covers the swap, and a better one just
+/// leaves token1 dust for the next unwind.
+pub fn size_unwind_swap(
+    zap: &ZapOutQuote,
+    minimum_token_1_received: u64,
+    token1_dust: u64,
+    quoter: &PoolQuoter,
+) -> Result<SwapQuote, AggregatorError> {
+    let amount_in = token1_dust
+        .checked_add(minimum_token_1_received)
+        .ok_or(AggregatorError::MathOverflow("token1 swap amount"))?;
+    let after_burn = PoolQuoter {
+        reserve_0: zap.reserves_after.0,
+        reserve_1: zap.reserves_after.1,
+        ..*quoter
+    };
+    after_burn.exact_in(amount_in, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lp::quote::TransferFee;
+
+    const QUOTER: PoolQuoter = PoolQuoter {
+        reserve_0: 1_000_000_000,
+        reserve_1: 2_000_000_000,
+        trade_fee_rate: 2500,
+        transfer_fee_0: TransferFee::NONE,
+        transfer_fee_1: TransferFee::NONE,
+    };
+
+    #[test]
+    fn swaps_slightly_more_than_half() {
+        let zap = zap_in(1_000_000, &QUOTER).unwrap();
+        assert_eq!(zap.swap_amount, 500_501);
+        assert_eq!(zap.swap_amount_out, 997_999);
+        assert_eq!(zap.remaining_amount, 499_499);
+    }
+
+    #[test]
+    fn leaves_less_dust_than_half_split() {
+        // WSOL that can't be paired with the swapped token1 at the post-swap ratio
+        let dust = |swap_amount: u64| {
+            let amount_out = QUOTER.exact_in(swap_amount, true).unwrap().amount_out as u128;
+            let needed = amount_out * (QUOTER.reserve_0 + swap_amount) as u128
+                / (QUOTER.reserve_1 as u128 - amount_out);
+            (1_000_000 - swap_amount) as u128 - needed
+        };
+
+        let zap = zap_in(1_000_000, &QUOTER).unwrap();
+        assert!(dust(zap.swap_amount) <= 1);
+        assert!(dust(500_000) > 500);
+    }
+
+    #[test]
+    fn deposit_fits_a_swap_filled_at_the_minimum() {
+        const LP_SUPPLY: u64 = 1_000_000_000;
+        let zap = zap_in(1_000_000, &QUOTER).unwrap();
+        let minimum_amount_out = zap.swap_amount_out * 95 / 100;
+        let deposit = size_deposit(&zap, minimum_amount_out, 0, LP_SUPPLY, &QUOTER).unwrap();
+        assert!(deposit.lp_token_amount > 0);
+        assert_eq!(deposit.maximum_token_1, minimum_amount_out);
+
+        // What the CPMM deposit pulls at the reserves left by a fill at the minimum and by a
+        // fill at the quote, never more than the vault holds after the worst fill
+        for amount_out in [minimum_amount_out, zap.swap_amount_out] {
+            let (token_0, token_1) = calculate_deposit_amounts(
+                deposit.lp_token_amount,
+                LP_SUPPLY,
+                QUOTER.reserve_0 + zap.swap_amount,
+                QUOTER.reserve_1 - amount_out,
+            )
+            .unwrap();
+            assert!(token_0 <= zap.remaining_amount);
+            assert!(token_1 <= minimum_amount_out);
+        }
+    }
+
+    #[test]
+    fn zap_out_burns_only_what_covers_target() {
+        let zap = zap_out(1_000_000, 1_000_000_000, 10_000_000_000, &QUOTER).unwrap();
+        assert!(zap.wsol_amount() >= 1_000_000);
+        assert!(zap.lp_amount < 1_000_000_000);
+
+        // One LP token less falls short of the target
+        let capped = zap_out(1_000_000, zap.lp_amount - 1, 10_000_000_000, &QUOTER).unwrap();
+        assert!(capped.wsol_amount() < 1_000_000);
+    }
+
+    #[test]
+    fn unwind_swap_spends_only_what_the_burn_guarantees() {
+        let zap = zap_out(1_000_000, 1_000_000_000, 10_000_000_000, &QUOTER).unwrap();
+        let minimum_token_1_received = zap.token_1_amount * 95 / 100;
+        let swap = size_unwind_swap(&zap, minimum_token_1_received, 7, &QUOTER).unwrap();
+        assert_eq!(swap.amount_in, minimum_token_1_received + 7);
+        assert!(swap.amount_in <= zap.token_1_amount);
+        assert!(swap.amount_out < zap.swap_amount_out);
+    }
+
+    #[test]
+    fn zap_out_caps_at_lp_balance() {
+        let zap = zap_out(u64::MAX, 1_000, 10_000_000_000, &QUOTER).unwrap();
+        assert_eq!(zap.lp_amount, 1_000);
+        assert_eq!(zap.token_0_amount, 100);
+        assert_eq!(zap.token_1_amount, 200);
+    }
+
+    #[test]
+    fn rejects_empty_pool() {
+        let empty = PoolQuoter {
+            reserve_0: 0,
+            ..QUOTER
+        };
+        assert!(matches!(
+            zap_in(1_000_000, &empty),
+            Err(AggregatorError::InsufficientLiquidity(_))
+        ));
+    }
+
+    #[test]
+    fn token1_transfer_fee_shifts_the_zap() {
+        let taxed = PoolQuoter {
+            transfer_fee_1: TransferFee {
+                basis_points: 500,
+                maximum_fee: u64::MAX,
+            },
+            ..QUOTER
+        };
+        let zap = zap_in(1_000_000, &taxed).unwrap();
+        // Both the swap payout and the deposit lose 5% of the token1, so more is swapped
+        assert!(zap.swap_amount > zap_in(1_000_000, &QUOTER).unwrap().swap_amount);
+        let swap = taxed.exact_in(zap.swap_amount, true).unwrap();
+        assert_eq!(
+            zap.reserves_after.1,
+            QUOTER.reserve_1 - zap.swap_amount_out - swap.transfer_fee_out
+        );
+
+        // The burn's token1 arrives net of the fee and is taxed again on the way back in
+        let out = zap_out(u64::MAX, 1_000, 10_000_000_000, &taxed).unwrap();
+        assert_eq!(out.token_1_amount, 190);
+        assert!(
+            out.wsol_amount()
+                < zap_out(u64::MAX, 1_000, 10_000_000_000, &QUOTER)
+                    .unwrap()
+                    .wsol_amount()
+        );
+    }
+}
@@ -1,101 +1,534 @@
+mod balance;
+mod buffer;
+mod cli;
 mod client;
+mod config;
 mod debug;
+mod error;
+mod fees;
+mod health;
+mod http;
+mod journal;
+mod logging;
 mod lp;
+mod metrics;
+mod nav;
+mod pool;
 mod raydium;
+mod signer;
+mod simulation;
+mod submitter;
 mod utils;
 mod vault;
+mod watch;
 
-use anchor_lang::prelude::declare_program;
-use std::env;
-use tokio::time::{interval, Duration};
-use utils::{POOL_ADDRESS, VAULT_PDA};
+use std::sync::Arc;
+
+use anchor_client::{
+    solana_client::nonblocking::rpc_client::RpcClient, solana_sdk::signer::Signer, Program,
+};
+use anchor_lang::prelude::{declare_program, Pubkey};
+use balance::{BalanceGuard, BatchPlan};
+use buffer::{LiquidityBuffer, Rebalance};
+use clap::Parser;
+use cli::{Cli, Command};
+use client::Clients;
+use error::Recovery;
+use signer::AggregatorSigner;
+use tokio::{
+    task::JoinHandle,
+    time::{interval, Duration},
+};
+use tracing::{error, info, info_span, warn, Instrument};
+use utils::VAULT_PDA;
+use vault::{Unwind, WithdrawOutcome, WithdrawQueue};
 
 // NOTE: declare_program! does not handle constants in IDL properly, just remove and define elsewhere
 declare_program!(memepool);
 
 #[tokio::main]
 async fn main() {
-    let aggregator_keypair = client::load_aggregator_keypair();
-    let (program, spl_program, raydium_program) = client::get_programs(&aggregator_keypair);
-
-    // Check for command line arguments
-    let args: Vec<String> = env::args().collect();
-    let debug_mode = args.len() > 1 && args[1] == "--debug";
-
-    if debug_mode {
-        // Run interactive debug loop
-        debug::run_interactive_test_loop(
-            &program,
-            &raydium_program,
-            &spl_program,
-            &aggregator_keypair,
+    let cli = Cli::parse();
+    let config = cli.load_config().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    logging::init(&config.log);
+    info!(
+        cluster = config.cluster().url(),
+        commitment = ?config.rpc.commitment.commitment,
+        "Starting aggregator"
+    );
+    if config.aggregator.dry_run {
+        warn!("Dry run: transactions are simulated, nothing is sent");
+    }
+    config::init(config);
+
+    let aggregator_signer = signer::load_aggregator_signer(config::get()).unwrap_or_else(|e| {
+        error!("Failed to load aggregator signer: {}", e);
+        std::process::exit(1);
+    });
+    let clients = client::get_clients(aggregator_signer);
+    // Only the long-running service funds the signer, serves probes and settles the journal
+    let balance_guard = match cli.command.unwrap_or_default() {
+        Command::Run => {
+            let balance_guard = BalanceGuard::new(clients.signer.pubkey()).unwrap_or_else(|e| {
+                error!("Failed to load funding keypair: {}", e);
+                std::process::exit(1);
+            });
+
+            if let Some(listen) = config::get().http.listen {
+                let probe = health::Probe {
+                    rpc: Arc::new(RpcClient::new_with_timeout_and_commitment(
+                        config::get().cluster().url().to_string(),
+                        health::PROBE_TIMEOUT,
+                        config::get().rpc.commitment,
+                    )),
+                    signer: clients.signer.pubkey(),
+                    config: config::get().health.clone(),
+                    max_tick_age_secs: config::get().max_tick_age_secs(),
+                };
+                if let Err(e) = http::serve(listen, probe).await {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+
+            // Dry runs leave no trace in the journal
+            if !config::get().aggregator.dry_run {
+                let in_flight = journal::open(&config::get().aggregator.journal_path)
+                    .unwrap_or_else(|e| {
+                        error!("{}", e);
+                        std::process::exit(1);
+                    });
+                if !in_flight.is_empty() {
+                    info!(
+                        records = in_flight.len(),
+                        "Reconciling in-flight journal records"
+                    );
+                    if let Err(e) = journal::reconcile(client::rpc(), in_flight).await {
+                        error!(error = %e, "Failed to reconcile journal");
+                    }
+                }
+            }
+            balance_guard
+        }
+        Command::Debug => {
+            let pools = pool::get_vault_pools(&clients.program, &clients.raydium_program)
+                .await
+                .expect("Failed to load vault pools");
+
+            // Run interactive debug loop
+            debug::run_interactive_test_loop(
+                &clients.program,
+                &clients.raydium_program,
+                &clients.spl_program,
+                &clients.signer,
+                &pools,
+            )
+            .await;
+            return;
+        }
+        Command::Pools => {
+            let pools = pool::get_vault_pools(&clients.program, &clients.raydium_program)
+                .await
+                .expect("Failed to load vault pools");
+
+            let now = utils::cluster_now()
+                .await
+                .expect("Failed to read the cluster clock");
+            for pool in &pools {
+                let state = &pool.pool_state;
+                let gates: Vec<String> = [
+                    state.check_deposit(),
+                    state.check_withdraw(),
+                    state.check_swap(now),
+                ]
+                .into_iter()
+                .filter_map(|check| check.err().map(|gate| gate.to_string()))
+                .collect();
+                let fees = match raydium::get_amm_config(&clients.raydium_program, state.amm_config)
+                    .await
+                {
+                    Ok(amm_config) => format!(
+                        "trade fee {} protocol fee {} fund fee {}",
+                        amm_config.trade_fee_rate,
+                        amm_config.protocol_fee_rate,
+                        amm_config.fund_fee_rate
+                    ),
+                    Err(e) => format!("amm config unavailable ({})", e),
+                };
+                println!(
+                    "pool {} vault pool {} lp mint {} vault lp balance {} {} status {}",
+                    pool.pool_address,
+                    pool.vault_pool,
+                    state.lp_mint,
+                    pool.vault_lp_balance(&clients.spl_program)
+                        .await
+                        .map_or_else(
+                            |e| format!("unavailable ({})", e),
+                            |balance| balance.to_string()
+                        ),
+                    fees,
+                    if gates.is_empty() {
+                        "open".to_string()
+                    } else {
+                        gates.join(", ")
+                    }
+                );
+            }
+            return;
+        }
+        Command::Nav => {
+            let pools = pool::get_vault_pools(&clients.program, &clients.raydium_program)
+                .await
+                .expect("Failed to load vault pools");
+            let nav = nav::calculate(&clients.program, &clients.spl_program, &pools)
+                .await
+                .unwrap_or_else(|e| {
+                    error!(error = %e, "Failed to calculate NAV");
+                    std::process::exit(1);
+                });
+            println!("{}", serde_json::to_string_pretty(&nav).unwrap());
+            return;
+        }
+    };
+
+    let mut interval = interval(Duration::from_secs(
+        config::get().aggregator.poll_interval_secs,
+    ));
+    let wake = config::get()
+        .aggregator
+        .subscribe
+        .then(|| watch::spawn(config::get().cluster().ws_url().to_string()));
+    let mut tick: usize = 0;
+    let mut lp_task = None;
+    let mut queue = WithdrawQueue::default();
+    let mut buffer = LiquidityBuffer::default();
+    loop {
+        match &wake {
+            Some(wake) => tokio::select! {
+                _ = interval.tick() => {}
+                _ = wake.notified() => {
+                    // Count the next poll from this tick
+                    interval.reset();
+                }
+            },
+            None => {
+                interval.tick().await;
+            }
+        }
+        tick = tick.wrapping_add(1);
+
+        let ok = run_tick(
+            &clients,
+            &balance_guard,
+            &mut lp_task,
+            &mut queue,
+            &mut buffer,
+            tick,
         )
+        .instrument(info_span!("tick", tick))
         .await;
-        return;
+        health::tick_finished(ok);
     }
+}
 
-    let mut interval = interval(Duration::from_secs(15));
-    loop {
-        interval.tick().await;
-
-        // Get pending withdraw requests (status = 0)
-        let withdraw_requests = vault::get_withdraw_requests(&program, Some(0), None).await;
-
-        if !withdraw_requests.is_empty() {
-            println!(
-                "Processing {} withdraw requests...",
-                withdraw_requests.len()
-            );
-            let results = vault::process_withdraw_requests_batch(
-                &program,
-                &raydium_program,
-                &spl_program,
-                &aggregator_keypair,
+/// One poll: fill pending withdraw requests, or move the vault's liquid SOL toward the buffer
+/// target. Returns whether every step succeeded. LP deposits and recalls run on their own task
+/// and log their own outcome, at most one is in flight and withdraw batches wait for it since
+/// both move available lamports.
+async fn run_tick(
+    clients: &Arc<Clients>,
+    balance_guard: &BalanceGuard,
+    lp_task: &mut Option<JoinHandle<()>>,
+    queue: &mut WithdrawQueue,
+    buffer: &mut LiquidityBuffer,
+    tick: usize,
+) -> bool {
+    let program = &clients.program;
+    let raydium_program = &clients.raydium_program;
+    let spl_program = &clients.spl_program;
+
+    // Refresh the pool registry every tick so newly registered pools are picked up
+    let pools = match pool::get_vault_pools(program, raydium_program).await {
+        Ok(pools) => pools,
+        Err(e) => {
+            metrics::record_rpc_error(&e);
+            error!(error = %e, "Failed to load vault pools");
+            return false;
+        }
+    };
+
+    let vault = match program
+        .account::<memepool::accounts::Vault>(*VAULT_PDA)
+        .await
+    {
+        Ok(vault) => vault,
+        Err(e) => {
+            error!(error = %e, "Failed to fetch vault account");
+            return false;
+        }
+    };
+    metrics::observe_vault(&vault);
+    health::observe_vault(&vault);
+    // Planned every tick so /readyz judges the buffer by its current target, acted on only
+    // once no withdraw requests are pending
+    let plan = buffer.plan(&config::get().buffer, &vault);
+    health::observe_buffer(&plan);
+
+    if metrics::is_enabled() {
+        observe_lp_metrics(raydium_program, spl_program, &pools).await;
+    }
+
+    // Get pending withdraw requests (status = 0)
+    let withdraw_requests = match vault::get_withdraw_requests(program, Some(0), None).await {
+        Ok(withdraw_requests) => withdraw_requests,
+        Err(e) => {
+            metrics::record_rpc_error(&e);
+            error!(error = %e, "Failed to load withdraw requests");
+            return false;
+        }
+    };
+    metrics::observe_withdraw_queue(&withdraw_requests);
+    health::observe_withdraw_queue(withdraw_requests.len());
+
+    if !withdraw_requests.is_empty() {
+        let aggregator = &config::get().aggregator;
+        let withdraw_requests = queue
+            .order(
+                client::rpc(),
+                aggregator.queue_policy,
+                aggregator.max_wait_slots,
                 withdraw_requests,
             )
             .await;
 
-            // Count successes and failures
-            let (successes, failures): (Vec<_>, Vec<_>) =
-                results.into_iter().partition(Result::is_ok);
-
-            println!(
-                "Batch processing complete. Successful: {}, Failed: {}",
-                successes.len(),
-                failures.len()
-            );
-        } else {
-            let vault = program
-                .account::<memepool::accounts::Vault>(*VAULT_PDA)
-                .await
-                .unwrap();
+        if let Some(task) = lp_task.take() {
+            if !task.is_finished() {
+                info!("Waiting for the LP rebalance in flight");
+            }
+            let _ = task.await;
+        }
 
-            println!(
-                "lamports {} avail {}",
-                vault.lamports, vault.available_lamports
-            );
+        let plan = BatchPlan::withdraw_batch(withdraw_requests.len(), pools.len());
+        if let Err(e) = balance_guard.ensure(client::rpc(), &plan).await {
+            metrics::record_rpc_error(&e);
+            error!(error = %e, "Not starting the withdraw batch");
+            return false;
+        }
 
-            let deposit_amount = 1_000_000; // 0.001 WSOL
-            if vault.available_lamports >= deposit_amount {
-                println!(
-                    "No pending withdraw requests found, but we have avail SOL, depositing into LP"
-                );
+        info!(
+            requests = withdraw_requests.len(),
+            "Processing withdraw requests"
+        );
+        let results =
+            vault::process_withdraw_requests_batch(clients, &pools, withdraw_requests).await;
 
-                match lp::process_lp_deposit(
-                    &program,
-                    &raydium_program,
-                    &spl_program,
-                    &aggregator_keypair,
-                    POOL_ADDRESS,
-                    deposit_amount,
-                )
-                .await
-                {
-                    Ok(_) => println!("Deposit successful"),
-                    Err(e) => println!("Deposit failed: {}", e),
-                };
-            } else {
-                println!("No pending withdraw requests found and no avail SOL, sleeping")
+        // Count outcomes and failures by how they should be handled
+        let (mut filled, mut partial, mut deferred) = (0, 0, 0);
+        let (mut retries, mut skips, mut alerts) = (0, 0, 0);
+        for result in results {
+            match result {
+                Ok(WithdrawOutcome::Filled { tx, lamports }) => {
+                    filled += 1;
+                    health::record_fill(&tx);
+                    buffer.record_fill(lamports);
+                }
+                Ok(WithdrawOutcome::PartiallyFilled { tx, lamports, .. }) => {
+                    partial += 1;
+                    health::record_fill(&tx);
+                    buffer.record_fill(lamports);
+                }
+                Ok(WithdrawOutcome::Deferred { .. }) => deferred += 1,
+                Err(e) => match e.recovery() {
+                    Recovery::Retry => retries += 1,
+                    Recovery::Skip => skips += 1,
+                    Recovery::Alert => {
+                        alerts += 1;
+                        error!(error = %e, program_logs = ?e.logs(), "ALERT");
+                    }
+                },
+            }
+        }
+
+        info!(
+            filled,
+            partial, deferred, retries, skips, alerts, "Batch processing complete"
+        );
+        return retries == 0 && alerts == 0;
+    }
+
+    info!(
+        lamports = vault.lamports,
+        available_lamports = vault.available_lamports,
+        "No pending withdraw requests"
+    );
+    // Nothing is being paid out, so holdings and the books should agree
+    check_nav(program, spl_program, &pools).await;
+
+    if pools.is_empty() {
+        info!("No vault pools registered, sleeping");
+        return true;
+    }
+    if lp_task.as_ref().is_some_and(|task| !task.is_finished()) {
+        info!("LP rebalance still in flight, sleeping");
+        return true;
+    }
+
+    let task = match plan.rebalance {
+        Rebalance::Hold => {
+            info!("Liquidity buffer on target, sleeping");
+            return true;
+        }
+        Rebalance::Deploy(amount) => {
+            if !can_afford(balance_guard, &BatchPlan::lp_deposit()).await {
+                return false;
+            }
+            // Rotate through the registered pools so positions are spread across all of them,
+            // passing over pools that would reject the swap or the deposit
+            let now = match utils::cluster_now().await {
+                Ok(now) => now,
+                Err(e) => {
+                    metrics::record_rpc_error(&e);
+                    error!(error = %e, "Not starting the LP rebalance");
+                    return false;
+                }
+            };
+            let open: Vec<_> = pools
+                .iter()
+                .filter(|pool| {
+                    let state = &pool.pool_state;
+                    state
+                        .check_swap(now)
+                        .and_then(|()| state.check_deposit())
+                        .is_ok()
+                })
+                .collect();
+            if open.is_empty() {
+                warn!("No registered pool accepts deposits, holding the buffer");
+                return true;
+            }
+            let pool_address = open[tick % open.len()].pool_address;
+            info!(pool = %pool_address, amount, "Deploying liquid SOL above the buffer into LP");
+            tokio::spawn(deploy(clients.clone(), pool_address, amount).in_current_span())
+        }
+        Rebalance::Recall(amount) => {
+            if !can_afford(balance_guard, &BatchPlan::lp_recall(pools.len())).await {
+                return false;
+            }
+            info!(amount, "Recalling LP to refill the buffer");
+            tokio::spawn(recall(clients.clone(), pools, amount).in_current_span())
+        }
+    };
+    *lp_task = Some(task);
+    true
+}
+
+async fn can_afford(balance_guard: &BalanceGuard, plan: &BatchPlan) -> bool {
+    match balance_guard.ensure(client::rpc(), plan).await {
+        Ok(()) => true,
+        Err(e) => {
+            metrics::record_rpc_error(&e);
+            error!(error = %e, "Not starting the LP rebalance");
+            false
+        }
+    }
+}
+
+async fn deploy(clients: Arc<Clients>, pool_address: Pubkey, amount: u64) {
+    match lp::process_lp_deposit(
+        &clients.program,
+        &clients.raydium_program,
+        &clients.spl_program,
+        &clients.signer,
+        pool_address,
+        amount,
+    )
+    .await
+    {
+        Ok(deposit) => info!(
+            lp_token_amount = deposit.lp_token_amount,
+            signature = %deposit.tx,
+            dust_token_0 = deposit.dust_token_0,
+            dust_token_1 = deposit.dust_token_1,
+            "Deposit successful"
+        ),
+        Err(e) if e.recovery() == Recovery::Alert => {
+            error!(error = %e, program_logs = ?e.logs(), "ALERT: Deposit failed")
+        }
+        Err(e) => {
+            metrics::record_rpc_error(&e);
+            warn!(error = %e, "Deposit failed")
+        }
+    }
+}
+
+async fn recall(clients: Arc<Clients>, pools: Vec<pool::RegisteredPool>, amount: u64) {
+    match vault::unwind_lp(
+        &clients.program,
+        &clients.raydium_program,
+        &clients.spl_program,
+        &clients.signer,
+        &pools,
+        amount,
+    )
+    .await
+    {
+        Ok(Unwind::Withdrew) => info!("Recall successful"),
+        Ok(Unwind::NoLiquidity) => info!("No LP to recall"),
+        Ok(Unwind::Failed(e)) => warn!(error = %e, "Recall failed"),
+        Err(e) => error!(error = %e, program_logs = ?e.logs(), "ALERT: Recall failed"),
+    }
+}
+
+/// Compare what the vault holds against `Vault.lamports`, alerting past `max_nav_drift_bps`
+async fn check_nav(
+    program: &Program<Arc<AggregatorSigner>>,
+    spl_program: &Program<Arc<AggregatorSigner>>,
+    pools: &[pool::RegisteredPool],
+) {
+    let nav = match nav::calculate(program, spl_program, pools).await {
+        Ok(nav) => nav,
+        Err(e) => {
+            metrics::record_rpc_error(&e);
+            warn!(error = %e, "Failed to calculate NAV");
+            return;
+        }
+    };
+    metrics::observe_nav(&nav);
+
+    if nav.drift_bps.unsigned_abs() > config::get().health.max_nav_drift_bps {
+        error!(
+            total_lamports = nav.total_lamports,
+            accounted_lamports = nav.accounted_lamports,
+            drift_lamports = nav.drift_lamports,
+            drift_bps = nav.drift_bps,
+            "ALERT: vault NAV drifted from Vault.lamports"
+        );
+    } else {
+        info!(
+            total_lamports = nav.total_lamports,
+            drift_lamports = nav.drift_lamports,
+            "Vault NAV"
+        );
+    }
+}
+
+/// Refresh the LP position gauges
+async fn observe_lp_metrics(
+    raydium_program: &Program<Arc<AggregatorSigner>>,
+    spl_program: &Program<Arc<AggregatorSigner>>,
+    pools: &[pool::RegisteredPool],
+) {
+    for pool in pools {
+        match lp::vault_lp_position(raydium_program, spl_program, pool).await {
+            Ok((lp_balance, value)) => {
+                metrics::observe_lp_position(&pool.pool_address, lp_balance, value)
+            }
+            Err(e) => {
+                metrics::record_rpc_error(&e);
+                warn!(pool = %pool.pool_address, error = %e, "Failed to value LP position for metrics")
             }
         }
     }
@@ -0,0 +1,350 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anchor_lang::prelude::Pubkey;
+use axum::http::header;
+use once_cell::sync::{Lazy, OnceCell};
+use prometheus::{
+    Encoder, Gauge, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use tracing::error;
+
+use crate::{
+    error::AggregatorError, journal::Action, memepool, nav::Nav, simulation::DRY_RUN_SIGNATURE,
+    vault::WithdrawOutcome,
+};
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+/// Set once the `/metrics` endpoint is listening, gates the RPC calls made only to feed it
+static LISTENING: OnceCell<SocketAddr> = OnceCell::new();
+
+struct Metrics {
+    registry: Registry,
+    vault_lamports: IntGauge,
+    vault_available_lamports: IntGauge,
+    vault_lp_balance: IntGaugeVec,
+    vault_lp_value: IntGaugeVec,
+    signer_balance: IntGauge,
+    nav: IntGauge,
+    nav_drift: IntGauge,
+    pending_withdraw_requests: IntGauge,
+    oldest_withdraw_request_age: Gauge,
+    withdraw_requests: IntCounterVec,
+    transactions: IntCounterVec,
+    swaps: IntCounterVec,
+    transaction_latency: HistogramVec,
+    rpc_errors: IntCounterVec,
+    /// When each pending request was first seen, requests carry no creation time on-chain
+    first_seen: Mutex<HashMap<Pubkey, Instant>>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("aggregator".to_string()), None)
+            .expect("valid metrics prefix");
+
+        let metrics = Self {
+            vault_lamports: IntGauge::new("vault_lamports", "Vault.lamports").unwrap(),
+            vault_available_lamports: IntGauge::new(
+                "vault_available_lamports",
+                "Vault.available_lamports",
+            )
+            .unwrap(),
+            vault_lp_balance: IntGaugeVec::new(
+                Opts::new("vault_lp_balance", "LP tokens held by the vault"),
+                &["pool"],
+            )
+            .unwrap(),
+            vault_lp_value: IntGaugeVec::new(
+                Opts::new(
+                    "vault_lp_value_lamports",
+                    "WSOL the vault's LP would unwind to, token1 side swapped back",
+                ),
+                &["pool"],
+            )
+            .unwrap(),
+            nav: IntGauge::new(
+                "vault_nav_lamports",
+                "Idle WSOL, LP share and stray token1 held by the vault, at spot",
+            )
+            .unwrap(),
+            nav_drift: IntGauge::new(
+                "vault_nav_drift_lamports",
+                "Vault NAV minus Vault.lamports, negative when holdings fall short",
+            )
+            .unwrap(),
+            signer_balance: IntGauge::new(
+                "signer_balance_lamports",
+                "Lamports held by the aggregator signer",
+            )
+            .unwrap(),
+            pending_withdraw_requests: IntGauge::new(
+                "pending_withdraw_requests",
+                "Withdraw requests waiting to be filled",
+            )
+            .unwrap(),
+            oldest_withdraw_request_age: Gauge::new(
+                "oldest_withdraw_request_age_seconds",
+                "Time since the oldest pending withdraw request was first seen",
+            )
+            .unwrap(),
+            withdraw_requests: IntCounterVec::new(
+                Opts::new(
+                    "withdraw_requests_processed_total",
+                    "Withdraw requests processed, by outcome",
+                ),
+                &["outcome"],
+            )
+            .unwrap(),
+            transactions: IntCounterVec::new(
+                Opts::new(
+                    "transactions_total",
+                    "Fill, swap+deposit and withdraw+swap transactions, by outcome",
+                ),
+                &["action", "outcome"],
+            )
+            .unwrap(),
+            swaps: IntCounterVec::new(
+                Opts::new("swaps_total", "Swaps sent with an LP deposit or withdraw"),
+                &["outcome"],
+            )
+            .unwrap(),
+            transaction_latency: HistogramVec::new(
+                HistogramOpts::new(
+                    "transaction_latency_seconds",
+                    "Time from the first send until a transaction lands",
+                )
+                .buckets(vec![0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0]),
+                &["action"],
+            )
+            .unwrap(),
+            rpc_errors: IntCounterVec::new(
+                Opts::new("rpc_errors_total", "RPC failures, by what was being done"),
+                &["context"],
+            )
+            .unwrap(),
+            first_seen: Mutex::new(HashMap::new()),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 14] = [
+            Box::new(metrics.vault_lamports.clone()),
+            Box::new(metrics.vault_available_lamports.clone()),
+            Box::new(metrics.vault_lp_balance.clone()),
+            Box::new(metrics.vault_lp_value.clone()),
+            Box::new(metrics.signer_balance.clone()),
+            Box::new(metrics.nav.clone()),
+            Box::new(metrics.nav_drift.clone()),
+            Box::new(metrics.pending_withdraw_requests.clone()),
+            Box::new(metrics.oldest_withdraw_request_age.clone()),
+            Box::new(metrics.withdraw_requests.clone()),
+            Box::new(metrics.transactions.clone()),
+            Box::new(metrics.swaps.clone()),
+            Box::new(metrics.transaction_latency.clone()),
+            Box::new(metrics.rpc_errors.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("metric registered once");
+        }
+        metrics
+    }
+}
+
+/// Called once `/metrics` is served, see `is_enabled`
+pub fn enable(listen: SocketAddr) {
+    let _ = LISTENING.set(listen);
+}
+
+/// Whether anything scrapes the metrics, so callers can skip RPC calls made only for them
+pub fn is_enabled() -> bool {
+    LISTENING.get().is_some()
+}
+
+/// `/metrics` handler, the Prometheus text format
+pub async fn render() -> ([(header::HeaderName, String); 1], Vec<u8>) {
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(e) = encoder.encode(&METRICS.registry.gather(), &mut body) {
+        error!(error = %e, "Failed to encode metrics");
+    }
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        body,
+    )
+}
+
+pub fn observe_vault(vault: &memepool::accounts::Vault) {
+    METRICS.vault_lamports.set(vault.lamports as i64);
+    METRICS
+        .vault_available_lamports
+        .set(vault.available_lamports as i64);
+}
+
+pub fn observe_lp_position(pool: &Pubkey, lp_balance: u64, value_lamports: u64) {
+    let pool = pool.to_string();
+    METRICS
+        .vault_lp_balance
+        .with_label_values(&[&pool])
+        .set(lp_balance as i64);
+    METRICS
+        .vault_lp_value
+        .with_label_values(&[&pool])
+        .set(value_lamports as i64);
+}
+
+pub fn observe_nav(nav: &Nav) {
+    METRICS
+        .nav
+        .set(nav.total_lamports.min(i64::MAX as u64) as i64);
+    METRICS.nav_drift.set(nav.drift_lamports);
+}
+
+pub fn observe_signer_balance(lamports: u64) {
+    METRICS.signer_balance.set(lamports as i64);
+}
+
+/// Update the pending count and age from the requests pending right now
+pub fn observe_withdraw_queue(requests: &[(Pubkey, memepool::accounts::WithdrawRequest)]) {
+    let pending: Vec<Pubkey> = requests.iter().map(|(pubkey, _)| *pubkey).collect();
+    let mut first_seen = METRICS.first_seen.lock().unwrap_or_else(|e| e.into_inner());
+    let oldest = oldest_age(&mut first_seen, &pending, Instant::now());
+
+    METRICS.pending_withdraw_requests.set(pending.len() as i64);
+    METRICS
+        .oldest_withdraw_request_age
+        .set(oldest.map_or(0.0, |age| age.as_secs_f64()));
+}
+
+/// Remember when each of `pending` was first seen, forget the rest, and return the age of
+/// the oldest
+fn oldest_age(
+    first_seen: &mut HashMap<Pubkey, Instant>,
+    pending: &[Pubkey],
+    now: Instant,
+) -> Option<Duration> {
+    first_seen.retain(|pubkey, _| pending.contains(pubkey));
+    for pubkey in pending {
+        first_seen.entry(*pubkey).or_insert(now);
+    }
+    first_seen
+        .values()
+        .min()
+        .map(|seen| now.saturating_duration_since(*seen))
+}
+
+pub fn record_withdraw(result: &Result<WithdrawOutcome, AggregatorError>) {
+    let outcome = match result {
+        Ok(WithdrawOutcome::Filled { .. }) => "filled",
+        Ok(WithdrawOutcome::PartiallyFilled { .. }) => "partially_filled",
+        Ok(WithdrawOutcome::Deferred { .. }) => "deferred",
+        Err(_) => "failed",
+    };
+    METRICS
+        .withdraw_requests
+        .with_label_values(&[outcome])
+        .inc();
+}
+
+/// Count a sent transaction, and how long it took to land when it did
+pub fn record_transaction(
+    action: Option<&Action>,
+    result: &Result<String, AggregatorError>,
+    elapsed: Duration,
+) {
+    let action = match action {
+        Some(Action::Fill { .. }) => "fill",
+        Some(Action::LpDeposit { .. }) => "lp_deposit",
+        Some(Action::LpWithdraw { .. }) => "lp_withdraw",
+        None => "other",
+    };
+    let outcome = match result {
+        Ok(signature) if signature == DRY_RUN_SIGNATURE => "dry_run",
+        Ok(_) => "landed",
+        Err(_) => "failed",
+    };
+
+    METRICS
+        .transactions
+        .with_label_values(&[action, outcome])
+        .inc();
+    if matches!(action, "lp_deposit" | "lp_withdraw") {
+        METRICS.swaps.with_label_values(&[outcome]).inc();
+    }
+    if outcome == "landed" {
+        METRICS
+            .transaction_latency
+            .with_label_values(&[action])
+            .observe(elapsed.as_secs_f64());
+    }
+}
+
+/// Count `err` when it is an RPC failure
+pub fn record_rpc_error(err: &AggregatorError) {
+    if let AggregatorError::Rpc { context, .. } = err {
+        METRICS.rpc_errors.with_label_values(&[context]).inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_age_tracks_first_sighting_and_forgets_filled() {
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let start = Instant::now();
+        let mut first_seen = HashMap::new();
+
+        assert_eq!(
+            oldest_age(&mut first_seen, &[a], start),
+            Some(Duration::ZERO)
+        );
+        let later = start + Duration::from_secs(30);
+        assert_eq!(
+            oldest_age(&mut first_seen, &[a, b], later),
+            Some(Duration::from_secs(30))
+        );
+        // `a` filled, only `b` is left
+        let last = start + Duration::from_secs(45);
+        assert_eq!(
+            oldest_age(&mut first_seen, &[b], last),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(oldest_age(&mut first_seen, &[], last), None);
+    }
+
+    #[test]
+    fn transactions_are_counted_by_action_and_outcome() {
+        let action = Action::LpWithdraw {
+            pool: Pubkey::new_unique(),
+            target_wsol: 1,
+            lp_token_amount: 1,
+            minimum_token_0: 0,
+            minimum_token_1: 0,
+            swap_amount: 0,
+            minimum_swap_out: 0,
+        };
+        let landed = METRICS
+            .transactions
+            .with_label_values(&["lp_withdraw", "landed"]);
+        let before = landed.get();
+
+        record_transaction(
+            Some(&action),
+            &Ok("signature".to_string()),
+            Duration::from_secs(2),
+        );
+
+        assert_eq!(landed.get(), before + 1);
+        assert!(METRICS.swaps.with_label_values(&["landed"]).get() >= 1);
+    }
+}
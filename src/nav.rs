@@ -0,0 +1,179 @@
+use std::{collections::HashSet, sync::Arc};
+
+use anchor_client::Program;
+use anchor_spl::token::spl_token;
+use serde::Serialize;
+
+use crate::{
+    error::AggregatorError,
+    memepool,
+    pool::RegisteredPool,
+    signer::AggregatorSigner,
+    utils::{get_token_account_balance_or_zero, VAULT_PDA, WSOL_MINT},
+};
+
+/// What the vault actually holds, valued in lamports, against what `Vault.lamports` says
+#[derive(Debug, Serialize)]
+pub struct Nav {
+    /// WSOL held by the vault outside LP
+    pub idle_lamports: u64,
+    pub positions: Vec<PositionNav>,
+    /// Token1 left in the vault's own accounts, e.g. swap dust from deposits
+    pub stray: Vec<StrayNav>,
+    pub total_lamports: u64,
+    /// `Vault.lamports`, what redemptions are priced from
+    pub accounted_lamports: u64,
+    /// `total_lamports - accounted_lamports`, negative when holdings fall short of the books
+    pub drift_lamports: i64,
+    pub drift_bps: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PositionNav {
+    pub pool: String,
+    pub lp_balance: u64,
+    pub lp_supply: u64,
+    /// The vault's pro-rata share of the pool's WSOL and token1 reserves
+    pub token_0: u64,
+    pub token_1: u64,
+    /// `token_0` plus `token_1` at the pool's spot price
+    pub value_lamports: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StrayNav {
+    pub mint: String,
+    pub amount: u64,
+    pub value_lamports: u64,
+}
+
+/// Value every vault holding across `pools` at spot prices. Marks to market without swap
+/// fees or price impact, so it reads higher than what unwinding would return.
+pub async fn calculate(
+    program: &Program<Arc<AggregatorSigner>>,
+    spl_program: &Program<Arc<AggregatorSigner>>,
+    pools: &[RegisteredPool],
+) -> Result<Nav, AggregatorError> {
+    let vault = program
+        .account::<memepool::accounts::Vault>(*VAULT_PDA)
+        .await
+        .map_err(|e| AggregatorError::from_client("Failed to fetch vault account", e))?;
+    let idle_lamports =
+        get_token_account_balance_or_zero(spl_program, &VAULT_PDA, &WSOL_MINT, &spl_token::ID)
+            .await?;
+
+    let mut positions = Vec::with_capacity(pools.len());
+    let mut stray = Vec::new();
+    let mut stray_mints = HashSet::new();
+    for pool in pools {
+        let pool_state = &pool.pool_state;
+        let vault_amounts = pool_state
+            .get_vault_amounts(spl_program)
+            .await
+            .map_err(|e| AggregatorError::from_client("Failed to get pool amounts", e))?;
+        let reserves = pool_state.reserves(vault_amounts)?;
+
+        let lp_balance = pool.vault_lp_balance(spl_program).await?;
+        let (token_0, token_1) = lp_share(lp_balance, pool_state.lp_supply, reserves);
+        positions.push(PositionNav {
+            pool: pool.pool_address.to_string(),
+            lp_balance,
+            lp_supply: pool_state.lp_supply,
+            token_0,
+            token_1,
+            value_lamports: token_0.saturating_add(in_lamports(token_1, reserves)),
+        });
+
+        let mint = pool_state.token_1_mint;
+        if stray_mints.insert(mint) {
+            let amount = get_token_account_balance_or_zero(
+                spl_program,
+                &VAULT_PDA,
+                &mint,
+                &pool_state.token_1_program,
+            )
+            .await?;
+            if amount > 0 {
+                stray.push(StrayNav {
+                    mint: mint.to_string(),
+                    amount,
+                    value_lamports: in_lamports(amount, reserves),
+                });
+            }
+        }
+    }
+
+    let total_lamports = positions
+        .iter()
+        .map(|position| position.value_lamports)
+        .chain(stray.iter().map(|stray| stray.value_lamports))
+        .fold(idle_lamports, u64::saturating_add);
+    let (drift_lamports, drift_bps) = drift(total_lamports, vault.lamports);
+
+    Ok(Nav {
+        idle_lamports,
+        positions,
+        stray,
+        total_lamports,
+        accounted_lamports: vault.lamports,
+        drift_lamports,
+        drift_bps,
+    })
+}
+
+/// `lp_balance`'s share of (token0, token1) `reserves`, rounded down like an LP withdraw
+fn lp_share(lp_balance: u64, lp_supply: u64, reserves: (u64, u64)) -> (u64, u64) {
+    if lp_supply == 0 {
+        return (0, 0);
+    }
+    let share = |reserve: u64| (reserve as u128 * lp_balance as u128 / lp_supply as u128) as u64;
+    (share(reserves.0), share(reserves.1))
+}
+
+/// Token1 `amount` in lamports at the spot price of (WSOL, token1) `reserves`
+fn in_lamports(amount: u64, reserves: (u64, u64)) -> u64 {
+    if reserves.1 == 0 {
+        return 0;
+    }
+    (amount as u128 * reserves.0 as u128 / reserves.1 as u128).min(u64::MAX as u128) as u64
+}
+
+/// (lamports, bps) by which `total` holdings exceed the `accounted` lamports
+fn drift(total: u64, accounted: u64) -> (i64, i64) {
+    let lamports = total as i128 - accounted as i128;
+    let bps = if accounted == 0 {
+        0
+    } else {
+        lamports * 10_000 / accounted as i128
+    };
+    (
+        lamports.clamp(i64::MIN as i128, i64::MAX as i128) as i64,
+        bps.clamp(i64::MIN as i128, i64::MAX as i128) as i64,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lp_share_is_pro_rata_of_reserves() {
+        assert_eq!(lp_share(25, 100, (1_000, 4_000)), (250, 1_000));
+        assert_eq!(lp_share(1, 3, (10, 10)), (3, 3));
+        assert_eq!(lp_share(0, 100, (1_000, 4_000)), (0, 0));
+        assert_eq!(lp_share(10, 0, (1_000, 4_000)), (0, 0));
+    }
+
+    #[test]
+    fn token1_is_valued_at_spot() {
+        assert_eq!(in_lamports(1_000, (1_000, 4_000)), 250);
+        assert_eq!(in_lamports(1_000, (1_000, 0)), 0);
+    }
+
+    #[test]
+    fn drift_is_signed() {
+        assert_eq!(drift(9_500, 10_000), (-500, -500));
+        assert_eq!(drift(10_100, 10_000), (100, 100));
+        assert_eq!(drift(100, 0), (100, 0));
+    }
+}
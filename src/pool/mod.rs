@@ -0,0 +1,3 @@
+pub mod registry;
+
+pub use registry::{get_vault_pools, RegisteredPool};
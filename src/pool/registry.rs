@@ -0,0 +1,87 @@
+use anchor_client::{solana_client::rpc_filter::RpcFilterType, Program};
+use anchor_lang::prelude::Pubkey;
+use anchor_spl::token::spl_token;
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::{
+    error::AggregatorError,
+    memepool,
+    raydium::{get_pool_state, PoolState},
+    signer::AggregatorSigner,
+    utils::{get_token_account_balance_or_zero, get_vault_pool_pda, VAULT_PDA, WSOL_MINT},
+};
+
+/// A CPMM pool the vault is allowed to LP into, as registered on-chain by a `VaultPool` account
+#[derive(Clone, Copy)]
+pub struct RegisteredPool {
+    pub vault_pool: Pubkey,
+    pub pool_address: Pubkey,
+    pub pool_state: PoolState,
+}
+
+impl RegisteredPool {
+    /// LP token balance held by VAULT_PDA for this pool, 0 if the ATA does not exist yet
+    pub async fn vault_lp_balance(
+        &self,
+        spl_program: &Program<Arc<AggregatorSigner>>,
+    ) -> Result<u64, AggregatorError> {
+        // CPMM LP mints are always legacy SPL tokens
+        get_token_account_balance_or_zero(
+            spl_program,
+            &VAULT_PDA,
+            &self.pool_state.lp_mint,
+            &spl_token::ID,
+        )
+        .await
+    }
+}
+
+/// Find every `VaultPool` owned by the memepool program and load the CPMM pool it points at
+pub async fn get_vault_pools(
+    program: &Program<Arc<AggregatorSigner>>,
+    raydium_program: &Program<Arc<AggregatorSigner>>,
+) -> Result<Vec<RegisteredPool>, AggregatorError> {
+    // Discriminator (8) + bump (1) + pool_id (32) = 41 bytes
+    const DATA_SIZE: usize = 8 + 1 + 32;
+
+    let vault_pools = program
+        .accounts::<memepool::accounts::VaultPool>(vec![RpcFilterType::DataSize(DATA_SIZE as u64)])
+        .await
+        .map_err(|e| AggregatorError::from_client("Failed to fetch vault pool accounts", e))?;
+
+    let mut pools = Vec::with_capacity(vault_pools.len());
+    for (vault_pool, account) in vault_pools {
+        // Only trust accounts sitting at the `vault_pool` PDA seeded with their own pool id
+        if get_vault_pool_pda(&account.pool_id) != vault_pool {
+            warn!(%vault_pool, pool = %account.pool_id, "Skipping vault pool: not the PDA for its pool");
+            continue;
+        }
+
+        match get_pool_state(raydium_program, account.pool_id).await {
+            // LP deposits, unwinds and quotes all treat token0 as WSOL
+            Ok(pool_state) if pool_state.token_0_mint != WSOL_MINT => warn!(
+                %vault_pool,
+                pool = %account.pool_id,
+                token_0_mint = %pool_state.token_0_mint,
+                "Skipping vault pool: token0 is not WSOL"
+            ),
+            Ok(pool_state) => pools.push(RegisteredPool {
+                vault_pool,
+                pool_address: account.pool_id,
+                pool_state,
+            }),
+            Err(e) => warn!(
+                %vault_pool,
+                pool = %account.pool_id,
+                error = %e,
+                "Skipping vault pool: failed to get pool state"
+            ),
+        }
+    }
+
+    // Keep a stable order across ticks so pool selection is deterministic
+    pools.sort_by_key(|pool| pool.pool_address);
+
+    Ok(pools)
+}
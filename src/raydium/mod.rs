@@ -1,11 +1,10 @@
-use std::rc::Rc;
-use anchor_client::{
-    Program,
-    solana_sdk::signature::Keypair,
-};
+use std::sync::Arc;
+use anchor_client::Program;
 use anchor_lang::prelude::*;
 use bytemuck::{Pod, Zeroable};
-use anchor_spl::token::TokenAccount;
+use anchor_spl::token_interface::TokenAccount;
+
+use crate::{error::AggregatorError, signer::AggregatorSigner};
 
 #[repr(C, packed)]
 #[derive(Default, Debug, Copy, Clone, Pod, Zeroable)]
@@ -42,7 +41,7 @@ impl anchor_lang::AccountDeserialize for PoolState {
         }
         // Skip 8-byte discriminator
         *buf = &buf[8..];
-        Ok(bytemuck::from_bytes::<PoolState>(buf).clone())
+        Ok(*bytemuck::from_bytes::<PoolState>(buf))
     }
 
     fn try_deserialize_unchecked(buf: &mut &[u8]) -> Result<Self> {
@@ -50,17 +49,192 @@ impl anchor_lang::AccountDeserialize for PoolState {
     }
 }
 
+/// Bits of `PoolState.status`, a set bit disables the operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolStatusBit {
+    Deposit = 0,
+    Withdraw = 1,
+    Swap = 2,
+}
+
+/// Why the CPMM program would reject an operation on a pool with `NotApproved`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum PoolGate {
+    #[error("deposits disabled")]
+    DepositDisabled,
+    #[error("withdraws disabled")]
+    WithdrawDisabled,
+    #[error("swaps disabled")]
+    SwapDisabled,
+    #[error("pool opens for swaps at {0}")]
+    NotOpen(u64),
+}
+
 impl PoolState {
-    pub async fn get_vault_amounts(&self, spl_program: &Program<Rc<Keypair>>) -> std::result::Result<(u64, u64), anchor_client::ClientError> {
+    pub fn is_enabled(&self, bit: PoolStatusBit) -> bool {
+        self.status & (1 << bit as u8) == 0
+    }
+
+    pub fn check_deposit(&self) -> std::result::Result<(), PoolGate> {
+        if !self.is_enabled(PoolStatusBit::Deposit) {
+            return Err(PoolGate::DepositDisabled);
+        }
+        Ok(())
+    }
+
+    pub fn check_withdraw(&self) -> std::result::Result<(), PoolGate> {
+        if !self.is_enabled(PoolStatusBit::Withdraw) {
+            return Err(PoolGate::WithdrawDisabled);
+        }
+        Ok(())
+    }
+
+    /// Swaps also wait for `open_time`, `now` being the cluster clock in unix seconds
+    pub fn check_swap(&self, now: u64) -> std::result::Result<(), PoolGate> {
+        if !self.is_enabled(PoolStatusBit::Swap) {
+            return Err(PoolGate::SwapDisabled);
+        }
+        if now < self.open_time {
+            return Err(PoolGate::NotOpen(self.open_time));
+        }
+        Ok(())
+    }
+
+    pub async fn get_vault_amounts(&self, spl_program: &Program<Arc<AggregatorSigner>>) -> std::result::Result<(u64, u64), anchor_client::ClientError> {
         let vault_0: TokenAccount = spl_program.account(self.token_0_vault).await?;
         let vault_1: TokenAccount = spl_program.account(self.token_1_vault).await?;
         Ok((vault_0.amount, vault_1.amount))
     }
+
+    /// Swap reserves: vault amounts minus the protocol and fund fees still owed out of them
+    pub fn reserves(&self, vault_amounts: (u64, u64)) -> std::result::Result<(u64, u64), AggregatorError> {
+        let reserve_0 = self.protocol_fees_token_0.checked_add(self.fund_fees_token_0)
+            .and_then(|fees| vault_amounts.0.checked_sub(fees))
+            .ok_or(AggregatorError::MathOverflow("token0 reserve"))?;
+        let reserve_1 = self.protocol_fees_token_1.checked_add(self.fund_fees_token_1)
+            .and_then(|fees| vault_amounts.1.checked_sub(fees))
+            .ok_or(AggregatorError::MathOverflow("token1 reserve"))?;
+        Ok((reserve_0, reserve_1))
+    }
+}
+
+/// Ring buffer length of `ObservationState.observations`
+pub const OBSERVATION_NUM: usize = 100;
+
+#[repr(C, packed)]
+#[derive(Default, Debug, Copy, Clone, Pod, Zeroable)]
+pub struct Observation {
+    pub block_timestamp: u64,
+    /// Time-weighted sums of token0 priced in token1 and token1 priced in token0, Q32.32,
+    /// wrapping on overflow
+    pub cumulative_token_0_price_x32: u128,
+    pub cumulative_token_1_price_x32: u128,
+}
+
+#[allow(dead_code)] // mirrors the on-chain layout
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+pub struct ObservationState {
+    pub initialized: u8,
+    /// Most recently written slot of `observations`
+    pub observation_index: u16,
+    pub pool_id: Pubkey,
+    pub observations: [Observation; OBSERVATION_NUM],
+    pub padding: [u64; 4],
+}
+
+impl anchor_lang::AccountDeserialize for ObservationState {
+    fn try_deserialize(buf: &mut &[u8]) -> Result<Self> {
+        if buf.len() < 8 + std::mem::size_of::<ObservationState>() {
+            return Err(error!(ErrorCode::AccountDiscriminatorNotFound));
+        }
+        // Skip 8-byte discriminator
+        *buf = &buf[8..];
+        Ok(*bytemuck::from_bytes::<ObservationState>(&buf[..std::mem::size_of::<ObservationState>()]))
+    }
+
+    fn try_deserialize_unchecked(buf: &mut &[u8]) -> Result<Self> {
+        Self::try_deserialize(buf)
+    }
+}
+
+#[allow(dead_code)] // mirrors the on-chain layout
+#[derive(Default, Debug, Clone, AnchorDeserialize)]
+pub struct AmmConfig {
+    pub bump: u8,
+    pub disable_create_pool: bool,
+    pub index: u16,
+    /// Denominated in hundredths of a bip (10^-6)
+    pub trade_fee_rate: u64,
+    pub protocol_fee_rate: u64,
+    pub fund_fee_rate: u64,
+    pub create_pool_fee: u64,
+    pub protocol_owner: Pubkey,
+    pub fund_owner: Pubkey,
+    pub padding: [u64; 16],
+}
+
+impl anchor_lang::AccountDeserialize for AmmConfig {
+    fn try_deserialize(buf: &mut &[u8]) -> Result<Self> {
+        if buf.len() < 8 {
+            return Err(error!(ErrorCode::AccountDiscriminatorNotFound));
+        }
+        // Skip 8-byte discriminator
+        *buf = &buf[8..];
+        AmmConfig::deserialize(buf).map_err(|_| error!(ErrorCode::AccountDidNotDeserialize))
+    }
+
+    fn try_deserialize_unchecked(buf: &mut &[u8]) -> Result<Self> {
+        Self::try_deserialize(buf)
+    }
 }
 
 pub async fn get_pool_state(
-    raydium_program: &Program<Rc<Keypair>>,
+    raydium_program: &Program<Arc<AggregatorSigner>>,
     pool_address: Pubkey,
 ) -> std::result::Result<PoolState, anchor_client::ClientError> {
     raydium_program.account::<PoolState>(pool_address).await
-} 
\ No newline at end of file
+}
+
+pub async fn get_amm_config(
+    raydium_program: &Program<Arc<AggregatorSigner>>,
+    amm_config: Pubkey,
+) -> std::result::Result<AmmConfig, anchor_client::ClientError> {
+    raydium_program.account::<AmmConfig>(amm_config).await
+} 
+
+pub async fn get_observation_state(
+    raydium_program: &Program<Arc<AggregatorSigner>>,
+    observation_key: Pubkey,
+) -> std::result::Result<ObservationState, anchor_client::ClientError> {
+    raydium_program.account::<ObservationState>(observation_key).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_bits_and_open_time_gate_operations() {
+        let pool = PoolState { status: 0, open_time: 1_000, ..PoolState::default() };
+        assert_eq!(pool.check_deposit(), Ok(()));
+        assert_eq!(pool.check_withdraw(), Ok(()));
+        assert_eq!(pool.check_swap(999), Err(PoolGate::NotOpen(1_000)));
+        assert_eq!(pool.check_swap(1_000), Ok(()));
+
+        let pool = PoolState { status: 0b101, ..pool };
+        assert_eq!(pool.check_deposit(), Err(PoolGate::DepositDisabled));
+        assert_eq!(pool.check_withdraw(), Ok(()));
+        assert_eq!(pool.check_swap(2_000), Err(PoolGate::SwapDisabled));
+    }
+
+    #[test]
+    fn reserves_net_out_owed_fees_and_reject_underflow() {
+        let pool = PoolState { protocol_fees_token_0: 3, fund_fees_token_0: 2, protocol_fees_token_1: 7, ..PoolState::default() };
+        assert_eq!(pool.reserves((100, 50)).unwrap(), (95, 43));
+        assert!(matches!(pool.reserves((4, 50)), Err(AggregatorError::MathOverflow("token0 reserve"))));
+
+        let pool = PoolState { fund_fees_token_1: u64::MAX, ..pool };
+        assert!(matches!(pool.reserves((100, u64::MAX)), Err(AggregatorError::MathOverflow("token1 reserve"))));
+    }
+}
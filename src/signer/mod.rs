@@ -0,0 +1,155 @@
+pub mod remote;
+
+use std::{env, fs, path::Path, sync::Arc};
+
+use anchor_client::solana_sdk::{
+    derivation_path::DerivationPath,
+    pubkey::Pubkey,
+    signature::{keypair_from_seed_and_derivation_path, Keypair, Signature},
+    signer::{Signer, SignerError},
+};
+use bip39::{Language, Mnemonic, Seed};
+
+use crate::config::{Config, SignerConfig};
+
+pub use remote::RemoteSigner;
+
+/// The aggregator's fee payer and authority, whichever backend holds the key
+#[derive(Clone)]
+pub struct AggregatorSigner(Arc<dyn Signer + Send + Sync>);
+
+impl AggregatorSigner {
+    pub fn new(signer: impl Signer + Send + Sync + 'static) -> Self {
+        Self(Arc::new(signer))
+    }
+}
+
+impl Signer for AggregatorSigner {
+    fn pubkey(&self) -> Pubkey {
+        self.0.pubkey()
+    }
+
+    fn try_pubkey(&self) -> Result<Pubkey, SignerError> {
+        self.0.try_pubkey()
+    }
+
+    fn sign_message(&self, message: &[u8]) -> Signature {
+        self.0.sign_message(message)
+    }
+
+    fn try_sign_message(&self, message: &[u8]) -> Result<Signature, SignerError> {
+        self.0.try_sign_message(message)
+    }
+
+    fn is_interactive(&self) -> bool {
+        self.0.is_interactive()
+    }
+}
+
+/// Load the signer selected by `config.signer`
+pub fn load_aggregator_signer(config: &Config) -> Result<AggregatorSigner, String> {
+    match &config.signer {
+        SignerConfig::File => read_keypair_file(&config.keypair_path).map(AggregatorSigner::new),
+        SignerConfig::Env { var } => keypair_from_base58(&read_env(var)?)
+            .map(AggregatorSigner::new)
+            .map_err(|e| format!("{}: {}", var, e)),
+        SignerConfig::Mnemonic {
+            phrase_var,
+            passphrase_var,
+            derivation_path,
+        } => {
+            let passphrase = match passphrase_var {
+                Some(var) => read_env(var)?,
+                None => String::new(),
+            };
+            keypair_from_mnemonic(&read_env(phrase_var)?, &passphrase, derivation_path)
+                .map(AggregatorSigner::new)
+                .map_err(|e| format!("{}: {}", phrase_var, e))
+        }
+        SignerConfig::Remote {
+            url,
+            pubkey,
+            token_var,
+            timeout_ms,
+        } => {
+            let token = token_var.as_deref().map(read_env).transpose()?;
+            RemoteSigner::new(url, *pubkey, token, *timeout_ms).map(AggregatorSigner::new)
+        }
+    }
+}
+
+/// Read a 64-byte JSON array keypair, as written by `solana-keygen`
+pub fn read_keypair_file(path: &Path) -> Result<Keypair, String> {
+    let keypair_str = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let keypair_bytes: Vec<u8> = serde_json::from_str(&keypair_str)
+        .map_err(|e| format!("Failed to parse keypair {}: {}", path.display(), e))?;
+    keypair_from_bytes(&keypair_bytes).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn read_env(var: &str) -> Result<String, String> {
+    env::var(var).map_err(|e| format!("Failed to read {}: {}", var, e))
+}
+
+fn keypair_from_bytes(bytes: &[u8]) -> Result<Keypair, String> {
+    if bytes.len() != 64 {
+        return Err(format!(
+            "keypair must be 64 bytes (32 secret + 32 public), got {}",
+            bytes.len()
+        ));
+    }
+    Keypair::from_bytes(bytes).map_err(|e| format!("invalid keypair: {}", e))
+}
+
+/// A keypair in the base58 form `solana-keygen` and wallets export
+pub fn keypair_from_base58(encoded: &str) -> Result<Keypair, String> {
+    let bytes = bs58::decode(encoded.trim())
+        .into_vec()
+        .map_err(|e| format!("invalid base58: {}", e))?;
+    keypair_from_bytes(&bytes)
+}
+
+/// Derive a keypair from an English BIP39 mnemonic at `derivation_path`, e.g. `m/44'/501'/0'/0'`
+pub fn keypair_from_mnemonic(
+    phrase: &str,
+    passphrase: &str,
+    derivation_path: &str,
+) -> Result<Keypair, String> {
+    let mnemonic = Mnemonic::from_phrase(phrase.trim(), Language::English)
+        .map_err(|e| format!("invalid mnemonic: {}", e))?;
+    let seed = Seed::new(&mnemonic, passphrase);
+    let derivation_path = DerivationPath::from_absolute_path_str(derivation_path)
+        .map_err(|e| format!("invalid derivation path {}: {}", derivation_path, e))?;
+    keypair_from_seed_and_derivation_path(seed.as_bytes(), Some(derivation_path))
+        .map_err(|e| format!("failed to derive keypair: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // BIP39 test vector, never holds funds
+    const PHRASE: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    #[test]
+    fn base58_round_trips() {
+        let keypair = Keypair::new();
+        let decoded = keypair_from_base58(&keypair.to_base58_string()).unwrap();
+        assert_eq!(decoded.pubkey(), keypair.pubkey());
+        assert!(keypair_from_base58("not base58 0OIl").is_err());
+        assert!(keypair_from_base58("abc").is_err());
+    }
+
+    #[test]
+    fn mnemonic_derivation_depends_on_path_and_passphrase() {
+        let first = keypair_from_mnemonic(PHRASE, "", "m/44'/501'/0'/0'").unwrap();
+        let again = keypair_from_mnemonic(PHRASE, "", "m/44'/501'/0'/0'").unwrap();
+        let second = keypair_from_mnemonic(PHRASE, "", "m/44'/501'/1'/0'").unwrap();
+        let protected = keypair_from_mnemonic(PHRASE, "secret", "m/44'/501'/0'/0'").unwrap();
+
+        assert_eq!(first.pubkey(), again.pubkey());
+        assert_ne!(first.pubkey(), second.pubkey());
+        assert_ne!(first.pubkey(), protected.pubkey());
+        assert!(keypair_from_mnemonic("abandon abandon", "", "m/44'/501'/0'/0'").is_err());
+    }
+}
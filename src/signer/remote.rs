@@ -0,0 +1,208 @@
+use std::time::Duration;
+
+use anchor_client::solana_sdk::{
+    pubkey::Pubkey,
+    signature::Signature,
+    signer::{Signer, SignerError},
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+/// Signs through an HTTP service holding the key, so it never sits on the aggregator host.
+///
+/// `POST {url}/sign` with `{"pubkey": <base58>, "message": <base64>}` and an optional bearer
+/// token must answer `{"signature": <base58>}`. Every signature is verified against `pubkey`
+/// before use.
+pub struct RemoteSigner {
+    sign_url: String,
+    pubkey: Pubkey,
+    token: Option<String>,
+    client: reqwest::Client,
+}
+
+#[derive(Serialize)]
+struct SignRequest<'a> {
+    pubkey: String,
+    message: &'a str,
+}
+
+#[derive(Deserialize)]
+struct SignResponse {
+    signature: String,
+}
+
+impl RemoteSigner {
+    pub fn new(
+        url: &str,
+        pubkey: Pubkey,
+        token: Option<String>,
+        timeout_ms: u64,
+    ) -> Result<Self, String> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(timeout_ms))
+            .build()
+            .map_err(|e| format!("Failed to build remote signer client: {}", e))?;
+
+        Ok(Self {
+            sign_url: format!("{}/sign", url.trim_end_matches('/')),
+            pubkey,
+            token,
+            client,
+        })
+    }
+
+    async fn sign(&self, message: &[u8]) -> Result<Signature, String> {
+        let encoded = BASE64.encode(message);
+        let mut request = self.client.post(&self.sign_url).json(&SignRequest {
+            pubkey: self.pubkey.to_string(),
+            message: &encoded,
+        });
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+
+        let response = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("remote signer request failed: {}", e))?
+            .json::<SignResponse>()
+            .await
+            .map_err(|e| format!("invalid remote signer response: {}", e))?;
+
+        let signature: Signature = response
+            .signature
+            .parse()
+            .map_err(|e| format!("invalid remote signature: {}", e))?;
+        if !signature.verify(self.pubkey.as_ref(), message) {
+            return Err(format!(
+                "remote signature doesn't verify for {}",
+                self.pubkey
+            ));
+        }
+        Ok(signature)
+    }
+}
+
+impl Signer for RemoteSigner {
+    fn try_pubkey(&self) -> Result<Pubkey, SignerError> {
+        Ok(self.pubkey)
+    }
+
+    /// `Signer` is synchronous, so the request blocks this worker thread of the multi-threaded
+    /// runtime while the other tasks move to the remaining workers
+    fn try_sign_message(&self, message: &[u8]) -> Result<Signature, SignerError> {
+        let handle = Handle::try_current()
+            .ok()
+            .filter(|handle| handle.runtime_flavor() == RuntimeFlavor::MultiThread)
+            .ok_or_else(|| {
+                SignerError::Custom("remote signer needs a multi-threaded tokio runtime".into())
+            })?;
+
+        tokio::task::block_in_place(|| handle.block_on(self.sign(message)))
+            .map_err(SignerError::Custom)
+    }
+
+    fn is_interactive(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_client::solana_sdk::signature::Keypair;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// Serve one `/sign` request, signing with `keypair`, and return the request it got
+    async fn mock_signer(listener: TcpListener, keypair: Keypair) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        let body_start = loop {
+            let read = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..read]);
+            if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end + 4;
+            }
+        };
+        let head = String::from_utf8_lossy(&request[..body_start]).to_lowercase();
+        let content_length: usize = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .map(|length| length.trim().parse().unwrap())
+            .unwrap();
+        while request.len() < body_start + content_length {
+            let read = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..read]);
+        }
+
+        let body: serde_json::Value = serde_json::from_slice(&request[body_start..]).unwrap();
+        let message = BASE64.decode(body["message"].as_str().unwrap()).unwrap();
+        let response = serde_json::json!({
+            "signature": keypair.sign_message(&message).to_string(),
+        })
+        .to_string();
+        stream
+            .write_all(
+                format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    response.len(),
+                    response
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+
+        String::from_utf8_lossy(&request).into_owned()
+    }
+
+    async fn signer_with_mock(
+        mock_key: Keypair,
+        pubkey: Pubkey,
+    ) -> (RemoteSigner, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(mock_signer(listener, mock_key));
+        let signer = RemoteSigner::new(&url, pubkey, Some("token".to_string()), 5_000).unwrap();
+        (signer, server)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn signs_through_the_service() {
+        let keypair = Keypair::new();
+        let pubkey = keypair.pubkey();
+        let (signer, server) = signer_with_mock(keypair, pubkey).await;
+
+        let signature = signer.try_sign_message(b"message").unwrap();
+
+        assert!(signature.verify(pubkey.as_ref(), b"message"));
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /sign "));
+        assert!(request.contains("authorization: Bearer token"));
+        assert!(request.contains(&pubkey.to_string()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejects_a_signature_from_another_key() {
+        let (signer, server) = signer_with_mock(Keypair::new(), Pubkey::new_unique()).await;
+
+        let err = signer.try_sign_message(b"message").unwrap_err();
+
+        assert!(err.to_string().contains("doesn't verify"));
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn needs_a_runtime() {
+        let signer =
+            RemoteSigner::new("http://127.0.0.1:1", Pubkey::new_unique(), None, 100).unwrap();
+        assert!(signer.try_sign_message(b"message").is_err());
+    }
+}
@@ -0,0 +1,275 @@
+use anchor_client::{
+    solana_client::{
+        nonblocking::rpc_client::RpcClient,
+        rpc_config::{RpcSimulateTransactionAccountsConfig, RpcSimulateTransactionConfig},
+        rpc_response::RpcSimulateTransactionResult,
+    },
+    solana_sdk::{
+        account::Account, instruction::Instruction, pubkey::Pubkey, transaction::Transaction,
+    },
+};
+use anchor_lang::solana_program::program_pack::Pack;
+use anchor_spl::{
+    token::spl_token::{self, state::Account as TokenAccount},
+    token_2022::{self, spl_token_2022::extension::AccountType},
+};
+use solana_account_decoder::UiAccountEncoding;
+use tracing::info;
+
+use crate::error::AggregatorError;
+
+/// Returned in place of a signature when a transaction was only simulated
+pub const DRY_RUN_SIGNATURE: &str = "dry-run";
+
+/// Simulate `instructions` signed by nobody against the latest blockhash, returning the
+/// post-simulation state of `accounts`
+pub async fn simulate(
+    rpc: &RpcClient,
+    payer: &Pubkey,
+    instructions: &[Instruction],
+    accounts: &[Pubkey],
+) -> Result<RpcSimulateTransactionResult, AggregatorError> {
+    let tx = Transaction::new_with_payer(instructions, Some(payer));
+
+    let accounts = (!accounts.is_empty()).then(|| RpcSimulateTransactionAccountsConfig {
+        encoding: Some(UiAccountEncoding::Base64),
+        addresses: accounts.iter().map(Pubkey::to_string).collect(),
+    });
+
+    rpc.simulate_transaction_with_config(
+        &tx,
+        RpcSimulateTransactionConfig {
+            sig_verify: false,
+            replace_recent_blockhash: true,
+            accounts,
+            ..RpcSimulateTransactionConfig::default()
+        },
+    )
+    .await
+    .map(|response| response.value)
+    .map_err(|e| AggregatorError::Rpc {
+        context: "Failed to simulate transaction",
+        message: e.to_string(),
+    })
+}
+
+/// Simulate instead of sending: print the program logs, compute units and the balance
+/// changes of every account the transaction writes
+pub async fn dry_run(
+    rpc: &RpcClient,
+    payer: &Pubkey,
+    instructions: &[Instruction],
+    context: &'static str,
+) -> Result<String, AggregatorError> {
+    let accounts = writable_accounts(payer, instructions);
+
+    let before = rpc
+        .get_multiple_accounts(&accounts)
+        .await
+        .map_err(|e| AggregatorError::Rpc {
+            context: "Failed to fetch accounts for dry run",
+            message: e.to_string(),
+        })?;
+
+    let result = simulate(rpc, payer, instructions, &accounts).await?;
+
+    info!(
+        context,
+        compute_units = result.units_consumed,
+        program_logs = ?result.logs.as_deref().unwrap_or_default(),
+        "Dry run"
+    );
+
+    if let Some(err) = result.err {
+        return Err(AggregatorError::from_transaction_error(
+            context,
+            err,
+            result.logs.unwrap_or_default(),
+        ));
+    }
+
+    let after = result.accounts.unwrap_or_default();
+    for ((address, before), after) in accounts.iter().zip(before).zip(after) {
+        let after = after.and_then(|account| account.decode::<Account>());
+        if let Some(change) = BalanceChange::between(before.as_ref(), after.as_ref()) {
+            info!(account = %address, %change, "Dry run balance change");
+        }
+    }
+
+    Ok(DRY_RUN_SIGNATURE.to_string())
+}
+
+/// Fee payer first, then every account the instructions write, deduplicated
+pub fn writable_accounts(payer: &Pubkey, instructions: &[Instruction]) -> Vec<Pubkey> {
+    let mut accounts = vec![*payer];
+    for meta in instructions.iter().flat_map(|ix| &ix.accounts) {
+        if meta.is_writable && !accounts.contains(&meta.pubkey) {
+            accounts.push(meta.pubkey);
+        }
+    }
+    accounts
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BalanceChange {
+    lamports: (u64, u64),
+    /// Token amounts when the account is an SPL token account
+    tokens: Option<(u64, u64)>,
+}
+
+impl BalanceChange {
+    /// Change between two snapshots of an account, `None` when nothing moved
+    fn between(before: Option<&Account>, after: Option<&Account>) -> Option<Self> {
+        let lamports = |account: Option<&Account>| account.map_or(0, |account| account.lamports);
+        let change = Self {
+            lamports: (lamports(before), lamports(after)),
+            tokens: match (token_amount(before), token_amount(after)) {
+                (None, None) => None,
+                (before, after) => Some((before.unwrap_or(0), after.unwrap_or(0))),
+            },
+        };
+        let moved = change.lamports.0 != change.lamports.1
+            || change.tokens.is_some_and(|(before, after)| before != after);
+        moved.then_some(change)
+    }
+}
+
+impl std::fmt::Display for BalanceChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let delta = |(before, after): (u64, u64)| after as i128 - before as i128;
+        write!(
+            f,
+            "lamports {} -> {} ({:+})",
+            self.lamports.0,
+            self.lamports.1,
+            delta(self.lamports)
+        )?;
+        if let Some(tokens) = self.tokens {
+            write!(
+                f,
+                ", tokens {} -> {} ({:+})",
+                tokens.0,
+                tokens.1,
+                delta(tokens)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Amount held by an SPL token (or Token-2022) account, read from the fixed account layout.
+/// Mints and multisigs share the owner but not the layout, so they report no amount.
+fn token_amount(account: Option<&Account>) -> Option<u64> {
+    let account = account?;
+    let data = &account.data;
+    let is_token_account = if account.owner == spl_token::ID {
+        data.len() == TokenAccount::LEN
+    } else if account.owner == token_2022::ID {
+        // Past the base layout, Token-2022 tags what the extensions extend
+        data.len() == TokenAccount::LEN
+            || data.get(TokenAccount::LEN) == Some(&(AccountType::Account as u8))
+    } else {
+        false
+    };
+    if !is_token_account {
+        return None;
+    }
+    // mint (32) + owner (32) + amount (8)
+    let amount = data.get(64..72)?;
+    Some(u64::from_le_bytes(amount.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_client::solana_sdk::instruction::AccountMeta;
+
+    fn token_account(lamports: u64, amount: u64) -> Account {
+        let mut data = vec![0; 165];
+        data[64..72].copy_from_slice(&amount.to_le_bytes());
+        Account {
+            lamports,
+            data,
+            owner: spl_token::ID,
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+
+    #[test]
+    fn writable_accounts_start_with_payer_and_are_deduplicated() {
+        let (payer, a, b) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let ix = |accounts| Instruction::new_with_bytes(Pubkey::new_unique(), &[], accounts);
+        let instructions = [
+            ix(vec![
+                AccountMeta::new(a, false),
+                AccountMeta::new_readonly(b, false),
+            ]),
+            ix(vec![
+                AccountMeta::new(payer, true),
+                AccountMeta::new(a, false),
+            ]),
+        ];
+        assert_eq!(writable_accounts(&payer, &instructions), vec![payer, a]);
+    }
+
+    #[test]
+    fn token_balance_change_is_reported() {
+        let change = BalanceChange::between(
+            Some(&token_account(2_039_280, 1_000)),
+            Some(&token_account(2_039_280, 400)),
+        )
+        .unwrap();
+        assert_eq!(change.tokens, Some((1_000, 400)));
+        assert_eq!(
+            change.to_string(),
+            "lamports 2039280 -> 2039280 (+0), tokens 1000 -> 400 (-600)"
+        );
+    }
+
+    #[test]
+    fn created_token_account_starts_at_zero() {
+        let change = BalanceChange::between(None, Some(&token_account(2_039_280, 5))).unwrap();
+        assert_eq!(change.lamports, (0, 2_039_280));
+        assert_eq!(change.tokens, Some((0, 5)));
+    }
+
+    #[test]
+    fn only_token_accounts_report_amounts() {
+        // A Token-2022 account with extensions past the base layout
+        let mut data = vec![0; 170];
+        data[64..72].copy_from_slice(&7u64.to_le_bytes());
+        data[165] = AccountType::Account as u8;
+        let extended = Account {
+            data,
+            owner: token_2022::ID,
+            ..token_account(2_039_280, 0)
+        };
+        assert_eq!(token_amount(Some(&extended)), Some(7));
+
+        // Mints, legacy and Token-2022 with extensions, hold no amount at bytes 64..72
+        let mint = Account {
+            data: vec![1; 82],
+            ..token_account(1_461_600, 0)
+        };
+        assert_eq!(token_amount(Some(&mint)), None);
+        let mut data = vec![1; 170];
+        data[165] = AccountType::Mint as u8;
+        let extended_mint = Account {
+            data,
+            owner: token_2022::ID,
+            ..mint
+        };
+        assert_eq!(token_amount(Some(&extended_mint)), None);
+    }
+
+    #[test]
+    fn unchanged_account_is_skipped() {
+        let account = token_account(2_039_280, 1_000);
+        assert_eq!(BalanceChange::between(Some(&account), Some(&account)), None);
+    }
+}
@@ -0,0 +1,184 @@
+use std::time::Duration;
+
+use anchor_client::{
+    solana_client::nonblocking::rpc_client::RpcClient,
+    solana_sdk::{
+        commitment_config::CommitmentConfig, pubkey::Pubkey, signature::Signature,
+        transaction::Transaction,
+    },
+    ClientError,
+};
+use tracing::{info, warn};
+
+use crate::{config::RpcConfig, error::AggregatorError, metrics};
+
+/// Outcome of one signed attempt
+pub enum Submission {
+    /// One of the signatures sent so far reached the confirm commitment
+    Landed(Signature),
+    /// The attempt's blockhash expired without any signature being seen, safe to re-sign
+    Expired,
+}
+
+enum SignatureStatus {
+    Landed(Signature),
+    /// Seen by the cluster but not yet at the confirm commitment
+    Processing,
+    Unseen,
+}
+
+/// Aborts a retry when the bytes at `offset` of `account` no longer match `expected`, e.g.
+/// a `WithdrawRequest` whose status moved on after an earlier attempt
+pub struct RetryGuard {
+    pub account: Pubkey,
+    pub offset: usize,
+    pub expected: Vec<u8>,
+}
+
+impl RetryGuard {
+    /// Whether the guarded bytes are still as expected, a closed account counts as changed
+    pub async fn holds(&self, rpc: &RpcClient) -> Result<bool, AggregatorError> {
+        let account = rpc
+            .get_account_with_commitment(&self.account, rpc.commitment())
+            .await
+            .map_err(|e| AggregatorError::Rpc {
+                context: "Failed to fetch retry guard account",
+                message: e.to_string(),
+            })?
+            .value;
+
+        Ok(account.is_some_and(|account| {
+            account
+                .data
+                .get(self.offset..self.offset + self.expected.len())
+                == Some(self.expected.as_slice())
+        }))
+    }
+}
+
+/// Tracks every signature sent for one logical transaction, so a re-signed attempt never
+/// loses sight of an earlier one that lands late
+pub struct Submitter<'a> {
+    rpc: &'a RpcClient,
+    commitment: CommitmentConfig,
+    poll_interval: Duration,
+    signatures: Vec<Signature>,
+}
+
+impl<'a> Submitter<'a> {
+    pub fn new(rpc: &'a RpcClient, config: &RpcConfig) -> Self {
+        Self {
+            rpc,
+            commitment: config.confirm_commitment,
+            poll_interval: Duration::from_millis(config.confirm_poll_interval_ms),
+            signatures: Vec::new(),
+        }
+    }
+
+    /// Every signature sent so far, oldest first
+    pub fn signatures(&self) -> &[Signature] {
+        &self.signatures
+    }
+
+    /// Send `tx` and poll until it, or an earlier attempt, lands or its blockhash expires
+    pub async fn submit(
+        &mut self,
+        tx: &Transaction,
+        context: &'static str,
+    ) -> Result<Submission, AggregatorError> {
+        let signature = tx.signatures[0];
+        self.signatures.push(signature);
+        info!(%signature, attempt = self.signatures.len(), "Sent transaction");
+
+        if let Err(e) = self.rpc.send_transaction(tx).await {
+            let err = AggregatorError::from_client(context, ClientError::SolanaClientError(e));
+            // Preflight failures never reach the cluster, anything else may still land
+            if !matches!(err, AggregatorError::Rpc { .. }) {
+                return Err(err);
+            }
+            metrics::record_rpc_error(&err);
+            warn!(%signature, error = %err, "Send failed, tracking it anyway");
+        }
+
+        loop {
+            match self.poll(tx, context).await {
+                Ok(Some(submission)) => return Ok(submission),
+                Ok(None) => {}
+                // Only lost sight of it for a moment, the transaction may still land
+                Err(e @ AggregatorError::Rpc { .. }) => {
+                    metrics::record_rpc_error(&e);
+                    warn!(%signature, error = %e, "Polling failed")
+                }
+                Err(e) => return Err(e),
+            }
+
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    /// `None` while the outcome is still open
+    async fn poll(
+        &self,
+        tx: &Transaction,
+        context: &'static str,
+    ) -> Result<Option<Submission>, AggregatorError> {
+        match self.status(context).await? {
+            SignatureStatus::Landed(signature) => return Ok(Some(Submission::Landed(signature))),
+            SignatureStatus::Processing => return Ok(None),
+            SignatureStatus::Unseen => {}
+        }
+
+        let blockhash_valid = self
+            .rpc
+            .is_blockhash_valid(&tx.message.recent_blockhash, CommitmentConfig::processed())
+            .await
+            .map_err(|e| AggregatorError::Rpc {
+                context: "Failed to check blockhash validity",
+                message: e.to_string(),
+            })?;
+        if blockhash_valid {
+            return Ok(None);
+        }
+
+        // Last look in case it landed right before the blockhash expired
+        Ok(match self.status(context).await? {
+            SignatureStatus::Landed(signature) => Some(Submission::Landed(signature)),
+            SignatureStatus::Processing => None,
+            SignatureStatus::Unseen => Some(Submission::Expired),
+        })
+    }
+
+    async fn status(&self, context: &'static str) -> Result<SignatureStatus, AggregatorError> {
+        let statuses = self
+            .rpc
+            .get_signature_statuses(&self.signatures)
+            .await
+            .map_err(|e| AggregatorError::Rpc {
+                context: "Failed to get signature statuses",
+                message: e.to_string(),
+            })?
+            .value;
+
+        let mut processing = false;
+        for (signature, status) in self.signatures.iter().zip(statuses) {
+            let Some(status) = status else { continue };
+            if let Some(err) = status.err {
+                return Err(AggregatorError::from_transaction_error(
+                    context,
+                    err,
+                    Vec::new(),
+                ));
+            }
+            if status.satisfies_commitment(self.commitment) {
+                return Ok(SignatureStatus::Landed(*signature));
+            }
+            processing = true;
+        }
+
+        Ok(if processing {
+            SignatureStatus::Processing
+        } else {
+            SignatureStatus::Unseen
+        })
+    }
+}
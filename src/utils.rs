@@ -1,16 +1,24 @@
-use crate::memepool;
-use anchor_client::solana_sdk::signature::Keypair;
-use anchor_client::Program;
+use crate::{client, config, error::AggregatorError, memepool, signer::AggregatorSigner};
+use anchor_client::{
+    solana_sdk::{account::from_account, clock::Clock, sysvar::clock},
+    ClientError, Program,
+};
 use anchor_lang::prelude::{pubkey, Pubkey};
-use anchor_spl::{associated_token::get_associated_token_address, token::TokenAccount};
+use anchor_spl::{
+    associated_token::get_associated_token_address_with_program_id, token_interface::TokenAccount,
+};
 use once_cell::sync::Lazy;
-use std::rc::Rc;
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-pub const CP_SWAP_PROGRAM: Pubkey = pubkey!("CPMDWBwJDtYax9qW7AyRuVC19Cc4L4Vcy4n2BHAbHkCW"); // DEVNET CPMM ADDRESS
 pub const WSOL_MINT: Pubkey = pubkey!("So11111111111111111111111111111111111111112");
 pub const _TEST_TOKEN_MINT: Pubkey = pubkey!("DcPRHwtoWCtzt8WwtD7VdMHvMLtHya7WPknH6kmUsUbw");
-pub const POOL_ADDRESS: Pubkey = pubkey!("88hgYfHGZcDfzdqMcG5cEbo82vd2SYkMEhYwAgZcL73C"); // TODO: scan for pool ids
-pub const MEMO_PROGRAM: Pubkey = pubkey!("MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr"); // TODO: given from logs
+
+pub static CP_SWAP_PROGRAM: Lazy<Pubkey> = Lazy::new(|| config::get().cp_swap_program());
+
+pub static MEMO_PROGRAM: Lazy<Pubkey> = Lazy::new(|| config::get().memo_program());
 
 pub static MEME_MINT_PDA: Lazy<Pubkey> = Lazy::new(|| {
     let seeds: [&[u8]; 1] = [b"meme"];
@@ -42,15 +50,63 @@ pub fn get_vault_pool_pda(pool: &Pubkey) -> Pubkey {
     pda
 }
 
+/// Balance of `owner`'s ATA for `token_mint`, `token_program` being the legacy SPL token
+/// program or Token-2022, whichever owns the mint
 pub async fn get_token_account_balance(
-    spl_program: &Program<Rc<Keypair>>,
+    spl_program: &Program<Arc<AggregatorSigner>>,
     owner: &Pubkey,
     token_mint: &Pubkey,
-) -> Result<u64, String> {
-    let token_account = get_associated_token_address(owner, token_mint);
+    token_program: &Pubkey,
+) -> Result<u64, AggregatorError> {
+    let token_account =
+        get_associated_token_address_with_program_id(owner, token_mint, token_program);
     spl_program
         .account::<TokenAccount>(token_account)
         .await
-        .map_err(|e| format!("Failed to get token account details: {}", e))
+        .map_err(|e| AggregatorError::from_client("Failed to get token account details", e))
         .map(|account| account.amount)
 }
+
+/// Like `get_token_account_balance`, but an ATA that doesn't exist yet holds nothing. Every
+/// other failure is still an error, so it can't pass for an empty account.
+pub async fn get_token_account_balance_or_zero(
+    spl_program: &Program<Arc<AggregatorSigner>>,
+    owner: &Pubkey,
+    token_mint: &Pubkey,
+    token_program: &Pubkey,
+) -> Result<u64, AggregatorError> {
+    let token_account =
+        get_associated_token_address_with_program_id(owner, token_mint, token_program);
+    match spl_program.account::<TokenAccount>(token_account).await {
+        Ok(account) => Ok(account.amount),
+        Err(ClientError::AccountNotFound) => Ok(0),
+        Err(e) => Err(AggregatorError::from_client(
+            "Failed to get token account details",
+            e,
+        )),
+    }
+}
+
+/// Unix time of the cluster's Clock sysvar, the clock on-chain checks like a pool's
+/// `open_time` are judged against
+pub async fn cluster_now() -> Result<u64, AggregatorError> {
+    let account =
+        client::rpc()
+            .get_account(&clock::ID)
+            .await
+            .map_err(|e| AggregatorError::Rpc {
+                context: "Failed to get clock sysvar",
+                message: e.to_string(),
+            })?;
+    let clock: Clock = from_account(&account).ok_or(AggregatorError::Rpc {
+        context: "Failed to decode clock sysvar",
+        message: format!("{} bytes", account.data.len()),
+    })?;
+    Ok(u64::try_from(clock.unix_timestamp).unwrap_or(0))
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
@@ -1,17 +1,31 @@
 use anchor_client::{
     solana_client::rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType},
-    solana_sdk::signature::Keypair,
     Program
 };
-use anchor_lang::prelude::Pubkey;
-use std::rc::Rc;
-use crate::memepool;
+use anchor_lang::{prelude::Pubkey, Discriminator};
+use std::sync::Arc;
+use crate::{error::AggregatorError, memepool, signer::AggregatorSigner};
+
+/// Skip discriminator (8) + pubkey (32) + bump (1)
+pub const WITHDRAW_REQUEST_STATUS_OFFSET: usize = 41;
 
 pub async fn get_withdraw_requests(
-    program: &Program<Rc<Keypair>>,
+    program: &Program<Arc<AggregatorSigner>>,
     status_filter: Option<u8>,
     pubkey_filter: Option<Pubkey>,
-) -> Vec<(Pubkey, memepool::accounts::WithdrawRequest)> {
+) -> Result<Vec<(Pubkey, memepool::accounts::WithdrawRequest)>, AggregatorError> {
+    program
+        .accounts(withdraw_request_filters(status_filter, pubkey_filter))
+        .await
+        .map_err(|e| AggregatorError::from_client("Failed to fetch withdraw requests", e))
+}
+
+/// Account filters selecting `WithdrawRequest`s, optionally by user and status. `program.accounts`
+/// adds the discriminator itself, raw RPC and subscriptions need `with_discriminator`.
+pub fn withdraw_request_filters(
+    status_filter: Option<u8>,
+    pubkey_filter: Option<Pubkey>,
+) -> Vec<RpcFilterType> {
     // Discriminator (8) + user Pubkey (32) + bump (1) + status (1) + meme_amt (8) + count (8) = 58 bytes
     const DATA_SIZE: usize = 8 + 32 + 1 + 1 + 8 + 8;
 
@@ -28,10 +42,19 @@ pub async fn get_withdraw_requests(
 
     if let Some(status) = status_filter {
         filters.push(RpcFilterType::Memcmp(Memcmp::new(
-            41, // Skip discriminator (8) + pubkey (32) + bump (1)
+            WITHDRAW_REQUEST_STATUS_OFFSET,
             MemcmpEncodedBytes::Bytes(vec![status]),
         )));
     }
 
-    program.accounts(filters).await.unwrap()
-} 
\ No newline at end of file
+    filters
+}
+
+/// `filters` plus the `WithdrawRequest` discriminator
+pub fn with_discriminator(mut filters: Vec<RpcFilterType>) -> Vec<RpcFilterType> {
+    filters.push(RpcFilterType::Memcmp(Memcmp::new(
+        0,
+        MemcmpEncodedBytes::Bytes(memepool::accounts::WithdrawRequest::DISCRIMINATOR.to_vec()),
+    )));
+    filters
+}
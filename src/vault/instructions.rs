@@ -1,20 +1,32 @@
 use anchor_client::{
-    solana_sdk::{signature::Keypair, system_program, signer::Signer},
+    solana_sdk::{system_program, signer::Signer},
     Program
 };
 use anchor_lang::prelude::Pubkey;
 use anchor_spl::token::spl_token;
-use std::rc::Rc;
+use std::sync::Arc;
 
-use crate::{memepool, utils::{MEME_MINT_PDA, VAULT_PDA, WSOL_MINT}};
+use crate::{
+    error::AggregatorError,
+    journal::Action,
+    lp::instructions::{memepool_instruction, TxComposer},
+    memepool,
+    signer::AggregatorSigner,
+    submitter::RetryGuard,
+    utils::{MEME_MINT_PDA, VAULT_PDA, WSOL_MINT},
+    vault::data::WITHDRAW_REQUEST_STATUS_OFFSET,
+};
+
+/// Fallback compute units for a fill when simulation doesn't report usage
+pub const VAULT_FILL_WITHDRAW_COMPUTE_UNITS: u32 = 100_000;
 
 pub async fn vault_fill_withdraw(
-    program: &Program<Rc<Keypair>>,
-    aggregator_keypair: &Keypair,
+    program: &Program<Arc<AggregatorSigner>>,
+    aggregator_signer: &AggregatorSigner,
     request_pubkey: Pubkey,
     withdraw_request: &memepool::accounts::WithdrawRequest,
     fill_lamports: u64,
-) -> Result<String, String> {
+) -> Result<String, AggregatorError> {
     let vault_address = *VAULT_PDA;
     let meme_mint = *MEME_MINT_PDA;
     let wsol_mint = WSOL_MINT;
@@ -35,7 +47,7 @@ pub async fn vault_fill_withdraw(
     );
 
     let accounts = memepool::client::accounts::VaultFillWithdraw {
-        aggregator: aggregator_keypair.pubkey(),
+        aggregator: aggregator_signer.pubkey(),
         withdrawer: withdraw_request.user,
         withdraw_request: request_pubkey,
         vault: vault_address,
@@ -50,16 +62,24 @@ pub async fn vault_fill_withdraw(
     };
 
     let args = memepool::client::args::VaultFillWithdraw { fill_lamports };
-    
-    let tx_builder = program
-        .request()
-        .args(args)
-        .accounts(accounts);
 
-    let tx = tx_builder
-        .send()
+    // Never re-sign a fill once the request moved on, an earlier attempt may have paid it
+    TxComposer::new(program, aggregator_signer)
+        .push(
+            memepool_instruction(accounts, args),
+            VAULT_FILL_WITHDRAW_COMPUTE_UNITS,
+        )
+        .journal(Action::Fill {
+            request: request_pubkey,
+            withdrawer: withdraw_request.user,
+            meme_amount: withdraw_request.meme_amt,
+            fill_lamports,
+        })
+        .retry_guard(RetryGuard {
+            account: request_pubkey,
+            offset: WITHDRAW_REQUEST_STATUS_OFFSET,
+            expected: vec![withdraw_request.status],
+        })
+        .send("Failed to send fill withdraw transaction")
         .await
-        .map_err(|e| format!("Failed to send fill withdraw transaction: {}", e))?;
-
-    Ok(tx.to_string())
-}
\ No newline at end of file
+}
@@ -1,6 +1,8 @@
 pub mod data;
 pub mod instructions;
+pub mod queue;
 pub mod service;
 
-pub use data::get_withdraw_requests;
-pub use service::process_withdraw_requests_batch;
\ No newline at end of file
+pub use data::{get_withdraw_requests, with_discriminator, withdraw_request_filters};
+pub use queue::WithdrawQueue;
+pub use service::{process_withdraw_requests_batch, unwind_lp, Unwind, WithdrawOutcome};
\ No newline at end of file
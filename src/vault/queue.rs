@@ -0,0 +1,198 @@
+use std::{cmp::Reverse, collections::HashMap};
+
+use anchor_client::solana_client::nonblocking::rpc_client::RpcClient;
+use anchor_lang::prelude::Pubkey;
+use tracing::warn;
+
+use crate::{config::QueuePolicy, memepool};
+
+type Requests = Vec<(Pubkey, memepool::accounts::WithdrawRequest)>;
+
+/// Orders pending withdraw requests by the configured `QueuePolicy`. `WithdrawRequest.count`
+/// only numbers a user's own requests, so requests are aged by the slot that created them.
+#[derive(Default)]
+pub struct WithdrawQueue {
+    created_slots: HashMap<Pubkey, u64>,
+}
+
+impl WithdrawQueue {
+    /// Sort `requests` for processing, looking up the creation slot of requests not seen before
+    pub async fn order(
+        &mut self,
+        rpc: &RpcClient,
+        policy: QueuePolicy,
+        max_wait_slots: u64,
+        mut requests: Requests,
+    ) -> Requests {
+        self.created_slots
+            .retain(|pubkey, _| requests.iter().any(|(request, _)| request == pubkey));
+        if requests.is_empty() {
+            return requests;
+        }
+
+        let slot = rpc.get_slot().await.ok();
+        let mut fallbacks = Vec::new();
+        for (pubkey, _) in &requests {
+            if self.created_slots.contains_key(pubkey) {
+                continue;
+            }
+            match created_slot(rpc, pubkey).await {
+                Some(created) => {
+                    self.created_slots.insert(*pubkey, created);
+                }
+                // Looked up again next tick, meanwhile it sorts as the newest
+                None => fallbacks.extend(slot.map(|slot| (*pubkey, slot))),
+            }
+        }
+
+        // Fallbacks only order this tick, they are never cached as the creation slot
+        let mut created_slots = self.created_slots.clone();
+        created_slots.extend(fallbacks);
+        sort(
+            policy,
+            &mut requests,
+            &created_slots,
+            slot.unwrap_or_default(),
+            max_wait_slots,
+        );
+        requests
+    }
+}
+
+/// Slot of the oldest transaction touching the request, the one that created it. Requests
+/// see a handful of transactions, well within one page of signatures.
+async fn created_slot(rpc: &RpcClient, request: &Pubkey) -> Option<u64> {
+    match rpc.get_signatures_for_address(request).await {
+        Ok(signatures) => signatures.last().map(|signature| signature.slot),
+        Err(e) => {
+            warn!(%request, error = %e, "Failed to look up withdraw request creation slot, using the current slot");
+            None
+        }
+    }
+}
+
+fn sort(
+    policy: QueuePolicy,
+    requests: &mut Requests,
+    created_slots: &HashMap<Pubkey, u64>,
+    slot: u64,
+    max_wait_slots: u64,
+) {
+    // Unknown creation slots sort last, ties go to the user's earlier request, then by address
+    let fifo = |(pubkey, request): &(Pubkey, memepool::accounts::WithdrawRequest)| {
+        (
+            created_slots.get(pubkey).copied().unwrap_or(u64::MAX),
+            request.user,
+            request.count,
+            *pubkey,
+        )
+    };
+
+    match policy {
+        QueuePolicy::Fifo | QueuePolicy::ProRata => requests.sort_by_key(fifo),
+        QueuePolicy::SmallestFirst => requests.sort_by_key(|entry| {
+            let overdue = created_slots
+                .get(&entry.0)
+                .is_some_and(|&created| slot.saturating_sub(created) > max_wait_slots);
+            // Overdue requests oldest first, then the rest smallest first
+            let size = if overdue { 0 } else { entry.1.meme_amt };
+            (Reverse(overdue), size, fifo(entry))
+        }),
+    }
+}
+
+/// Each request's share of `available` lamports in proportion to what it is owed, never more
+/// than owed. Rounding down leaves the remainder in the vault.
+pub fn pro_rata(required: &[u64], available: u64) -> Vec<u64> {
+    let total: u128 = required.iter().map(|&lamports| lamports as u128).sum();
+    if total <= available as u128 {
+        return required.to_vec();
+    }
+    required
+        .iter()
+        .map(|&lamports| (lamports as u128 * available as u128 / total) as u64)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(
+        user: Pubkey,
+        count: u64,
+        meme_amt: u64,
+    ) -> (Pubkey, memepool::accounts::WithdrawRequest) {
+        (
+            Pubkey::new_unique(),
+            memepool::accounts::WithdrawRequest {
+                user,
+                bump: 0,
+                status: 0,
+                meme_amt,
+                count,
+            },
+        )
+    }
+
+    fn amounts(requests: &Requests) -> Vec<u64> {
+        requests
+            .iter()
+            .map(|(_, request)| request.meme_amt)
+            .collect()
+    }
+
+    #[test]
+    fn fifo_orders_by_creation_slot_then_count() {
+        let user = Pubkey::new_unique();
+        let mut requests = vec![
+            request(user, 2, 30),
+            request(Pubkey::new_unique(), 0, 10),
+            request(user, 1, 20),
+            request(Pubkey::new_unique(), 0, 40),
+        ];
+        let created_slots = HashMap::from([
+            (requests[0].0, 100),
+            (requests[1].0, 50),
+            (requests[2].0, 100),
+        ]);
+
+        sort(QueuePolicy::Fifo, &mut requests, &created_slots, 200, 1_000);
+
+        assert_eq!(amounts(&requests), vec![10, 20, 30, 40]);
+    }
+
+    #[test]
+    fn smallest_first_serves_overdue_requests_first() {
+        let mut requests = vec![
+            request(Pubkey::new_unique(), 0, 30),
+            request(Pubkey::new_unique(), 0, 500),
+            request(Pubkey::new_unique(), 0, 10),
+            request(Pubkey::new_unique(), 0, 900),
+        ];
+        let created_slots = HashMap::from([
+            (requests[0].0, 1_900),
+            (requests[1].0, 100),
+            (requests[2].0, 1_950),
+            (requests[3].0, 50),
+        ]);
+
+        sort(
+            QueuePolicy::SmallestFirst,
+            &mut requests,
+            &created_slots,
+            2_000,
+            1_000,
+        );
+
+        assert_eq!(amounts(&requests), vec![900, 500, 10, 30]);
+    }
+
+    #[test]
+    fn pro_rata_shares_shortfalls_evenly() {
+        assert_eq!(pro_rata(&[100, 300], 1_000), vec![100, 300]);
+        assert_eq!(pro_rata(&[100, 300], 200), vec![50, 150]);
+        assert_eq!(pro_rata(&[1, 1, 1], 2), vec![0, 0, 0]);
+        assert_eq!(pro_rata(&[], 0), Vec::<u64>::new());
+    }
+}
@@ -1,135 +1,595 @@
-use anchor_client::{solana_sdk::signature::Keypair, Program};
+use anchor_client::Program;
 use anchor_lang::prelude::Pubkey;
 use anchor_spl::token::Mint;
-use std::rc::Rc;
+use std::{cmp::Reverse, fmt, sync::Arc};
+use tokio::sync::Semaphore;
+use tracing::{field, info, info_span, warn, Instrument, Span};
 
 use crate::{
-    lp, memepool,
-    utils::{MEME_MINT_PDA, POOL_ADDRESS, VAULT_PDA},
-    vault::instructions::vault_fill_withdraw,
+    client::Clients,
+    config::{self, QueuePolicy},
+    error::{AggregatorError, Recovery},
+    lp, memepool, metrics,
+    pool::RegisteredPool,
+    signer::AggregatorSigner,
+    utils::{MEME_MINT_PDA, VAULT_PDA},
+    vault::{instructions::vault_fill_withdraw, queue},
 };
 
+/// Where a withdraw request ended up after one processing cycle
+#[derive(Debug)]
+pub enum WithdrawOutcome {
+    /// Paid the full amount owed
+    Filled { tx: String, lamports: u64 },
+    /// Paid everything the vault had available, the rest is still owed
+    PartiallyFilled {
+        tx: String,
+        lamports: u64,
+        required: u64,
+    },
+    /// Nothing paid this cycle
+    Deferred { reason: String },
+}
+
+impl fmt::Display for WithdrawOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Filled { tx, lamports } => write!(f, "filled {} lamports (tx: {})", lamports, tx),
+            Self::PartiallyFilled {
+                tx,
+                lamports,
+                required,
+            } => write!(
+                f,
+                "partially filled {} of {} lamports (tx: {})",
+                lamports, required, tx
+            ),
+            Self::Deferred { reason } => write!(f, "deferred, {}", reason),
+        }
+    }
+}
+
+/// Steps a request moves through until it reaches a `WithdrawOutcome`
+enum WithdrawStep {
+    /// Vault is short on available lamports, unwind LP to cover `shortfall`
+    Unwind { shortfall: u64 },
+    /// The unwind landed, re-read the vault's available lamports
+    Refresh,
+    /// Pay `amount` lamports to the request
+    Fill { amount: u64 },
+}
+
 pub async fn process_withdraw_request(
-    program: &Program<Rc<Keypair>>,
-    raydium_program: &Program<Rc<Keypair>>,
-    spl_program: &Program<Rc<Keypair>>,
-    aggregator_keypair: &Keypair,
+    program: &Program<Arc<AggregatorSigner>>,
+    raydium_program: &Program<Arc<AggregatorSigner>>,
+    spl_program: &Program<Arc<AggregatorSigner>>,
+    aggregator_signer: &AggregatorSigner,
+    pools: &[RegisteredPool],
     request_pubkey: Pubkey,
     withdraw_request: memepool::accounts::WithdrawRequest,
-) -> Result<(), String> {
-    // Get the vault account
-    let vault = program
-        .account::<memepool::accounts::Vault>(*VAULT_PDA)
-        .await
-        .map_err(|e| format!("Failed to fetch vault account: {}", e))?;
-
-    // Get meme token supply
-    let mint = spl_program
-        .account::<Mint>(*MEME_MINT_PDA)
-        .await
-        .map_err(|e| format!("Failed to fetch mint account: {}", e))?;
-    let meme_token_supply = mint.supply;
+) -> Result<WithdrawOutcome, AggregatorError> {
+    let vault = get_vault(program).await?;
+    let meme_token_supply = get_meme_supply(spl_program).await?;
+    let required_sol =
+        required_lamports(withdraw_request.meme_amt, vault.lamports, meme_token_supply)?;
 
-    // Calculate required SOL (  withdraw_request.meme_amt * (vault.lamports / meme_token_supply) )
-    let required_sol = (withdraw_request.meme_amt as u64)
-        .checked_mul(vault.lamports)
-        .and_then(|product| product.checked_div(meme_token_supply))
-        .ok_or("Failed to calculate required SOL: overflow or division by zero")?;
+    Span::current().record("required_lamports", required_sol);
 
-    // Get the LP token balance of VAULT_PDA
-    let pool_state = crate::raydium::get_pool_state(raydium_program, POOL_ADDRESS)
-        .await
-        .map_err(|e| format!("Failed to get pool state: {}", e))?;
+    let mut available_lamports = vault.available_lamports;
+    let mut step = if required_sol > available_lamports {
+        WithdrawStep::Unwind {
+            shortfall: required_sol - available_lamports,
+        }
+    } else {
+        WithdrawStep::Fill {
+            amount: required_sol,
+        }
+    };
 
-    let lp_balance =
-        crate::utils::get_token_account_balance(spl_program, &*VAULT_PDA, &pool_state.lp_mint)
-            .await
-            .unwrap_or(0); // If there's an error getting the balance, assume 0
-
-    println!("LP token balance owned by vault: {}", lp_balance);
-
-    // If required_sol <= available_lamports OR we don't have any LP tokens to burn,
-    // then just send vault.available_lamports
-    if required_sol <= vault.available_lamports || lp_balance == 0 {
-        let send_amount = if required_sol <= vault.available_lamports {
-            // We have enough, so send the required amount
-            println!(
-                "Processing withdraw request {} with {} SOL",
-                request_pubkey, required_sol
-            );
-            required_sol
-        } else {
-            // We don't have enough but no LP tokens to burn, so send what we have
-            println!(
-                "Insufficient SOL (needed {}, have {}) and no LP tokens to burn. Sending available lamports.",
-                required_sol, vault.available_lamports
-            );
-            vault.available_lamports
+    loop {
+        step = match step {
+            WithdrawStep::Unwind { shortfall } => {
+                match unwind_lp(
+                    program,
+                    raydium_program,
+                    spl_program,
+                    aggregator_signer,
+                    pools,
+                    shortfall,
+                )
+                .await?
+                {
+                    Unwind::Withdrew => WithdrawStep::Refresh,
+                    // Nothing to burn, pay out what the vault already has
+                    Unwind::NoLiquidity => {
+                        warn!(
+                            required_lamports = required_sol,
+                            available_lamports,
+                            "Insufficient SOL and no LP tokens to burn, sending available lamports"
+                        );
+                        WithdrawStep::Fill {
+                            amount: available_lamports,
+                        }
+                    }
+                    // Hold off rather than partially fill a request the LP could have covered
+                    Unwind::Failed(e) => {
+                        return Ok(WithdrawOutcome::Deferred {
+                            reason: format!("LP unwind failed: {}", e),
+                        })
+                    }
+                }
+            }
+            WithdrawStep::Refresh => {
+                // The unwind's `TxComposer::send` only returns once the `Submitter` has seen it
+                // land at `rpc.confirm_commitment`, so the vault read here reflects it
+                available_lamports = get_vault(program).await?.available_lamports;
+                info!(
+                    available_lamports,
+                    "Vault available lamports after LP unwind"
+                );
+                WithdrawStep::Fill {
+                    amount: required_sol.min(available_lamports),
+                }
+            }
+            WithdrawStep::Fill { amount } => {
+                return fill(
+                    program,
+                    aggregator_signer,
+                    request_pubkey,
+                    &withdraw_request,
+                    amount,
+                    required_sol,
+                )
+                .await;
+            }
         };
+    }
+}
 
-        // Call fill_withdraw_request with the calculated amount
-        let tx = vault_fill_withdraw(
-            program,
-            aggregator_keypair,
-            request_pubkey,
-            &withdraw_request,
-            send_amount,
-        )
-        .await?;
+/// Pay `amount` of the `required` lamports owed to the request
+async fn fill(
+    program: &Program<Arc<AggregatorSigner>>,
+    aggregator_signer: &AggregatorSigner,
+    request_pubkey: Pubkey,
+    withdraw_request: &memepool::accounts::WithdrawRequest,
+    amount: u64,
+    required: u64,
+) -> Result<WithdrawOutcome, AggregatorError> {
+    if amount == 0 {
+        return Ok(WithdrawOutcome::Deferred {
+            reason: "vault has no available lamports".to_string(),
+        });
+    }
 
-        println!("Fill withdraw request transaction: {}", tx);
-        Ok(())
+    info!(
+        fill_lamports = amount,
+        required_lamports = required,
+        "Filling withdraw request"
+    );
+
+    let tx = vault_fill_withdraw(
+        program,
+        aggregator_signer,
+        request_pubkey,
+        withdraw_request,
+        amount,
+    )
+    .await?;
+
+    Span::current().record("signature", tx.as_str());
+    info!(signature = %tx, "Fill withdraw request landed");
+
+    Ok(if amount < required {
+        WithdrawOutcome::PartiallyFilled {
+            tx,
+            lamports: amount,
+            required,
+        }
     } else {
-        // process_lp_withdraw
-        println!(
-            "Initiating LP withdraw of {} tokens...",
-            required_sol - vault.available_lamports
+        WithdrawOutcome::Filled {
+            tx,
+            lamports: amount,
+        }
+    })
+}
+
+pub enum Unwind {
+    /// At least one LP withdraw landed
+    Withdrew,
+    /// The vault holds no LP in any registered pool
+    NoLiquidity,
+    /// Every attempt failed with an error worth retrying later
+    Failed(AggregatorError),
+}
+
+/// Burn LP across the registered pools, largest vault position first, until the expected
+/// WSOL covers `shortfall`
+pub async fn unwind_lp(
+    program: &Program<Arc<AggregatorSigner>>,
+    raydium_program: &Program<Arc<AggregatorSigner>>,
+    spl_program: &Program<Arc<AggregatorSigner>>,
+    aggregator_signer: &AggregatorSigner,
+    pools: &[RegisteredPool],
+    shortfall: u64,
+) -> Result<Unwind, AggregatorError> {
+    let mut positions = Vec::with_capacity(pools.len());
+    let mut last_error = None;
+    for pool in pools {
+        // A balance that can't be read is not an empty position
+        let pool_lp_balance = match pool.vault_lp_balance(spl_program).await {
+            Ok(balance) => balance,
+            Err(e) if e.recovery() == Recovery::Alert => return Err(e),
+            Err(e) => {
+                metrics::record_rpc_error(&e);
+                warn!(pool = %pool.pool_address, error = %e, "Failed to read vault LP balance");
+                last_error = Some(e);
+                continue;
+            }
+        };
+        info!(
+            pool = %pool.pool_address,
+            lp_balance = pool_lp_balance,
+            "LP token balance owned by vault"
         );
-        let withdraw_tx = lp::process_lp_withdraw(
-            &program,
-            &raydium_program,
-            &spl_program,
-            &aggregator_keypair,
-            POOL_ADDRESS,
-        )
-        .await?;
+        if pool_lp_balance > 0 {
+            positions.push((pool, pool_lp_balance));
+        }
+    }
+    positions.sort_by_key(|&(_, balance)| Reverse(balance));
 
-        Err(format!(
-            "Withdrew LP tokens (tx: {}), retry withdraw request",
-            withdraw_tx
-        ))
+    let mut remaining = shortfall;
+    let mut withdrew = false;
+    for (pool, _) in positions {
+        match lp::process_lp_withdraw(
+            program,
+            raydium_program,
+            spl_program,
+            aggregator_signer,
+            pool.pool_address,
+            remaining,
+        )
+        .await
+        {
+            Ok(withdraw) => {
+                info!(
+                    pool = %pool.pool_address,
+                    lp_burned = withdraw.lp_burned,
+                    expected_wsol = withdraw.expected_wsol,
+                    signature = %withdraw.tx,
+                    "Unwound LP"
+                );
+                withdrew = true;
+                remaining = remaining.saturating_sub(withdraw.expected_wsol);
+                if remaining == 0 {
+                    break;
+                }
+            }
+            Err(e) if e.recovery() == Recovery::Alert => return Err(e),
+            Err(e) => {
+                metrics::record_rpc_error(&e);
+                warn!(pool = %pool.pool_address, error = %e, "LP withdraw failed");
+                last_error = Some(e);
+            }
+        }
     }
+
+    Ok(match (withdrew, last_error) {
+        (true, _) => Unwind::Withdrew,
+        (false, Some(e)) => Unwind::Failed(e),
+        (false, None) => Unwind::NoLiquidity,
+    })
+}
+
+async fn get_vault(
+    program: &Program<Arc<AggregatorSigner>>,
+) -> Result<memepool::accounts::Vault, AggregatorError> {
+    program
+        .account::<memepool::accounts::Vault>(*VAULT_PDA)
+        .await
+        .map_err(|e| AggregatorError::from_client("Failed to fetch vault account", e))
+}
+
+async fn get_meme_supply(
+    spl_program: &Program<Arc<AggregatorSigner>>,
+) -> Result<u64, AggregatorError> {
+    spl_program
+        .account::<Mint>(*MEME_MINT_PDA)
+        .await
+        .map(|mint| mint.supply)
+        .map_err(|e| AggregatorError::from_client("Failed to fetch mint account", e))
+}
+
+/// Lamports owed for `meme_amt`: meme_amt * (vault_lamports / meme_supply)
+fn required_lamports(
+    meme_amt: u64,
+    vault_lamports: u64,
+    meme_supply: u64,
+) -> Result<u64, AggregatorError> {
+    (meme_amt as u128 * vault_lamports as u128)
+        .checked_div(meme_supply as u128)
+        .and_then(|result| u64::try_from(result).ok())
+        .ok_or(AggregatorError::MathOverflow("required SOL"))
+}
+
+/// How many requests from the head of the queue the vault's available lamports cover
+/// together. Those can be filled concurrently, the rest may need LP unwinds, which contend
+/// for the same positions.
+fn covered_by_available(required: &[u64], available_lamports: u64) -> usize {
+    let mut remaining = available_lamports;
+    required
+        .iter()
+        .take_while(|&&lamports| match remaining.checked_sub(lamports) {
+            Some(left) => {
+                remaining = left;
+                true
+            }
+            None => false,
+        })
+        .count()
 }
 
+/// Lamports owed to each request from the head of the queue, priced off one vault snapshot
+/// and cut short at the first one that can't be priced
+async fn quote_requests(
+    clients: &Clients,
+    withdraw_requests: &[(Pubkey, memepool::accounts::WithdrawRequest)],
+) -> Result<(Vec<u64>, u64), AggregatorError> {
+    let vault = get_vault(&clients.program).await?;
+    let meme_supply = get_meme_supply(&clients.spl_program).await?;
+    let required = withdraw_requests
+        .iter()
+        .map_while(|(_, request)| {
+            required_lamports(request.meme_amt, vault.lamports, meme_supply).ok()
+        })
+        .collect();
+    Ok((required, vault.available_lamports))
+}
+
+fn request_span(
+    request_pubkey: &Pubkey,
+    withdraw_request: &memepool::accounts::WithdrawRequest,
+) -> Span {
+    info_span!(
+        "withdraw_request",
+        request = %request_pubkey,
+        meme_amount = withdraw_request.meme_amt,
+        required_lamports = field::Empty,
+        signature = field::Empty,
+        program_logs = field::Empty,
+    )
+}
+
+fn log_result(span: &Span, result: &Result<WithdrawOutcome, AggregatorError>) {
+    span.in_scope(|| match result {
+        Ok(outcome) => info!(%outcome, "Processed withdraw request"),
+        Err(e) => {
+            metrics::record_rpc_error(e);
+            warn!(error = %e, "Failed to process withdraw request")
+        }
+    });
+    metrics::record_withdraw(result);
+}
+
+/// Fill the requests the vault already holds lamports for concurrently, up to
+/// `max_concurrent_fills` at a time, then process the rest one after another in the order
+/// given. Under `QueuePolicy::ProRata` every request is filled at once with its share instead.
+/// Results come back in the order given.
 pub async fn process_withdraw_requests_batch(
-    program: &Program<Rc<Keypair>>,
-    raydium_program: &Program<Rc<Keypair>>,
-    spl_program: &Program<Rc<Keypair>>,
-    aggregator_keypair: &Keypair,
+    clients: &Arc<Clients>,
+    pools: &[RegisteredPool],
     withdraw_requests: Vec<(Pubkey, memepool::accounts::WithdrawRequest)>,
-) -> Vec<Result<(), String>> {
-    let mut results = Vec::with_capacity(withdraw_requests.len());
+) -> Vec<Result<WithdrawOutcome, AggregatorError>> {
+    let quote = match quote_requests(clients, &withdraw_requests).await {
+        Ok(quote) => Some(quote),
+        Err(e) => {
+            metrics::record_rpc_error(&e);
+            warn!(error = %e, "Failed to price withdraw requests, processing them one at a time");
+            None
+        }
+    };
 
-    for (request_pubkey, withdraw_request) in withdraw_requests {
-        println!("Starting to process request {}", request_pubkey);
+    if config::get().aggregator.queue_policy == QueuePolicy::ProRata {
+        if let Some((required, available_lamports)) = quote
+            .as_ref()
+            .filter(|(required, _)| required.len() == withdraw_requests.len())
+        {
+            return process_pro_rata(
+                clients,
+                pools,
+                withdraw_requests,
+                required,
+                *available_lamports,
+            )
+            .await;
+        }
+    }
+
+    let required = match quote {
+        Some((required, available_lamports)) => {
+            let covered = covered_by_available(&required, available_lamports);
+            required[..covered].to_vec()
+        }
+        None => Vec::new(),
+    };
+    info!(
+        concurrent = required.len(),
+        sequential = withdraw_requests.len() - required.len(),
+        "Split withdraw requests"
+    );
+
+    let mut withdraw_requests = withdraw_requests.into_iter();
+    let fills = withdraw_requests
+        .by_ref()
+        .zip(required)
+        .map(|((request_pubkey, withdraw_request), required)| {
+            (request_pubkey, withdraw_request, required, required)
+        })
+        .collect();
+    let mut results = fill_concurrently(clients, fills).await;
 
+    for (request_pubkey, withdraw_request) in withdraw_requests {
+        let span = request_span(&request_pubkey, &withdraw_request);
         let result = process_withdraw_request(
-            program,
-            raydium_program,
-            spl_program,
-            aggregator_keypair,
+            &clients.program,
+            &clients.raydium_program,
+            &clients.spl_program,
+            &clients.signer,
+            pools,
             request_pubkey,
             withdraw_request,
         )
+        .instrument(span.clone())
         .await;
+        log_result(&span, &result);
+        results.push(result);
+    }
+
+    results
+}
+
+/// Unwind LP for the whole batch's shortfall, then fill every request with the same share of
+/// what it is owed from what the vault holds
+async fn process_pro_rata(
+    clients: &Arc<Clients>,
+    pools: &[RegisteredPool],
+    withdraw_requests: Vec<(Pubkey, memepool::accounts::WithdrawRequest)>,
+    required: &[u64],
+    mut available_lamports: u64,
+) -> Vec<Result<WithdrawOutcome, AggregatorError>> {
+    let total = required
+        .iter()
+        .fold(0u64, |total, &lamports| total.saturating_add(lamports));
 
-        match &result {
-            Ok(_) => println!("Successfully processed request {}", request_pubkey),
-            Err(e) => println!("Failed to process request {}: {}", request_pubkey, e),
+    if total > available_lamports {
+        let deferred = |reason: String| -> Vec<_> {
+            withdraw_requests
+                .iter()
+                .map(|_| {
+                    Ok(WithdrawOutcome::Deferred {
+                        reason: reason.clone(),
+                    })
+                })
+                .collect()
+        };
+
+        match unwind_lp(
+            &clients.program,
+            &clients.raydium_program,
+            &clients.spl_program,
+            &clients.signer,
+            pools,
+            total - available_lamports,
+        )
+        .await
+        {
+            Ok(Unwind::Withdrew) => match get_vault(&clients.program).await {
+                Ok(vault) => available_lamports = vault.available_lamports,
+                Err(e) => return deferred(format!("vault refresh failed: {}", e)),
+            },
+            Ok(Unwind::NoLiquidity) => {}
+            // Hold off rather than shortchange every request the LP could have covered
+            Ok(Unwind::Failed(e)) => return deferred(format!("LP unwind failed: {}", e)),
+            // Surface the alert once, the rest of the batch waits
+            Err(e) => {
+                let mut results = deferred("LP unwind needs attention".to_string());
+                results[0] = Err(e);
+                return results;
+            }
         }
+    }
+
+    let shares = queue::pro_rata(required, available_lamports);
+    info!(
+        requests = withdraw_requests.len(),
+        required_lamports = total,
+        available_lamports,
+        "Filling withdraw requests pro rata"
+    );
 
+    let fills = withdraw_requests
+        .into_iter()
+        .zip(shares)
+        .zip(required)
+        .map(|(((request_pubkey, withdraw_request), share), &required)| {
+            (request_pubkey, withdraw_request, share, required)
+        })
+        .collect();
+    fill_concurrently(clients, fills).await
+}
+
+/// Pay each `(request, amount, required)` on its own task, up to `max_concurrent_fills` at a
+/// time. The amounts must fit the vault's available lamports together.
+async fn fill_concurrently(
+    clients: &Arc<Clients>,
+    fills: Vec<(Pubkey, memepool::accounts::WithdrawRequest, u64, u64)>,
+) -> Vec<Result<WithdrawOutcome, AggregatorError>> {
+    let permits = Arc::new(Semaphore::new(
+        config::get().aggregator.max_concurrent_fills.max(1),
+    ));
+    let tasks: Vec<_> = fills
+        .into_iter()
+        .map(|(request_pubkey, withdraw_request, amount, required)| {
+            let clients = clients.clone();
+            let permits = permits.clone();
+            let span = request_span(&request_pubkey, &withdraw_request);
+            span.record("required_lamports", required);
+            let fill = async move {
+                let _permit = permits.acquire_owned().await;
+                fill(
+                    &clients.program,
+                    &clients.signer,
+                    request_pubkey,
+                    &withdraw_request,
+                    amount,
+                    required,
+                )
+                .await
+            };
+            (span.clone(), tokio::spawn(fill.instrument(span)))
+        })
+        .collect();
+
+    let mut results = Vec::with_capacity(tasks.len());
+    for (span, task) in tasks {
+        let result = task
+            .await
+            .unwrap_or_else(|e| Err(AggregatorError::TaskFailed(e.to_string())));
+        log_result(&span, &result);
         results.push(result);
     }
-
     results
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn covered_by_available_stops_at_the_first_shortfall() {
+        assert_eq!(covered_by_available(&[3, 4, 2], 10), 3);
+        assert_eq!(covered_by_available(&[3, 4, 2], 7), 2);
+        // A smaller request behind one that doesn't fit keeps its place in the queue
+        assert_eq!(covered_by_available(&[3, 8, 1], 10), 1);
+        assert_eq!(covered_by_available(&[5], 4), 0);
+        assert_eq!(covered_by_available(&[], 4), 0);
+    }
+
+    #[test]
+    fn required_lamports_is_pro_rata() {
+        assert_eq!(required_lamports(250, 1_000, 1_000).unwrap(), 250);
+        assert_eq!(required_lamports(1, 10, 3).unwrap(), 3);
+        // Half of a 1e16 supply against 20k SOL, the product is far past u64
+        assert_eq!(
+            required_lamports(
+                5_000_000_000_000_000,
+                20_000_000_000_000,
+                10_000_000_000_000_000
+            )
+            .unwrap(),
+            10_000_000_000_000
+        );
+        // Only a share larger than u64 can overflow
+        assert!(required_lamports(u64::MAX, 2, 1).is_err());
+        assert!(required_lamports(1, 1, 0).is_err());
+    }
+}
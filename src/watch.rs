@@ -0,0 +1,112 @@
+use std::{sync::Arc, time::Duration};
+
+use anchor_client::{
+    solana_client::{
+        nonblocking::pubsub_client::{PubsubClient, PubsubClientError},
+        rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    },
+    solana_sdk::account::Account,
+};
+use anchor_lang::AccountDeserialize;
+use futures::StreamExt;
+use solana_account_decoder::UiAccountEncoding;
+use tokio::{sync::Notify, time::sleep};
+use tracing::{debug, info, warn};
+
+use crate::{config, health, memepool, metrics, utils::VAULT_PDA, vault};
+
+/// Wait before the first resubscribe after a dropped connection, doubled per failed attempt
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Subscribe to pending withdraw requests and the vault over the websocket at `ws_url`,
+/// resubscribing whenever the connection drops. The returned `Notify` fires on every new or
+/// changed pending request; notifications that arrive mid-tick coalesce into one wakeup.
+pub fn spawn(ws_url: String) -> Arc<Notify> {
+    let wake = Arc::new(Notify::new());
+    tokio::spawn(run(ws_url, wake.clone()));
+    wake
+}
+
+async fn run(ws_url: String, wake: Arc<Notify>) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match subscribe(&ws_url, &wake).await {
+            // Held a subscription until the server dropped it, reconnect promptly
+            Ok(()) => {
+                backoff = MIN_BACKOFF;
+                warn!(
+                    retry_in = ?backoff,
+                    "Account subscription closed, polling until resubscribed"
+                );
+            }
+            Err(e) => warn!(
+                error = %e,
+                retry_in = ?backoff,
+                "Account subscription failed, polling until resubscribed"
+            ),
+        }
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Hold `programSubscribe` on pending withdraw requests and `accountSubscribe` on the vault
+/// PDA until either stream ends. Errors only while subscribing.
+async fn subscribe(ws_url: &str, wake: &Notify) -> Result<(), PubsubClientError> {
+    let commitment = Some(config::get().rpc.commitment);
+    let account_config = RpcAccountInfoConfig {
+        encoding: Some(UiAccountEncoding::Base64),
+        commitment,
+        ..RpcAccountInfoConfig::default()
+    };
+
+    let client = PubsubClient::new(ws_url).await?;
+    let (mut requests, _unsubscribe_requests) = client
+        .program_subscribe(
+            &memepool::ID,
+            Some(RpcProgramAccountsConfig {
+                filters: Some(vault::with_discriminator(vault::withdraw_request_filters(
+                    Some(0),
+                    None,
+                ))),
+                account_config: account_config.clone(),
+                with_context: None,
+            }),
+        )
+        .await?;
+    let (mut vault, _unsubscribe_vault) = client
+        .account_subscribe(&VAULT_PDA, Some(account_config))
+        .await?;
+    info!(ws_url, "Subscribed to withdraw requests and the vault");
+
+    // Requests may have landed while unsubscribed
+    wake.notify_one();
+
+    loop {
+        tokio::select! {
+            update = requests.next() => {
+                let Some(update) = update else { return Ok(()) };
+                debug!(
+                    request = update.value.pubkey,
+                    slot = update.context.slot,
+                    "Withdraw request updated"
+                );
+                wake.notify_one();
+            }
+            update = vault.next() => {
+                let Some(update) = update else { return Ok(()) };
+                // Keeps the gauges and the buffer readiness check current between ticks
+                match update.value.decode::<Account>().and_then(|account| {
+                    memepool::accounts::Vault::try_deserialize(&mut account.data.as_slice()).ok()
+                }) {
+                    Some(vault) => {
+                        metrics::observe_vault(&vault);
+                        health::observe_vault(&vault);
+                    }
+                    None => warn!(slot = update.context.slot, "Failed to decode vault update"),
+                }
+            }
+        }
+    }
+}